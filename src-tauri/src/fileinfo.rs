@@ -1,4 +1,4 @@
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime};
 use rawler::decoders::RawDecodeParams;
 use rawler::exif::Exif;
 use rawler::RawFile;
@@ -10,6 +10,8 @@ use std::sync::{Arc, Mutex};
 
 pub struct ImageCandidate {
     pub path: PathBuf,
+    /// Make and model of the camera
+    pub camera: String,
     exif: Arc<Mutex<Exif>>,
 }
 
@@ -26,10 +28,28 @@ impl ImageCandidate {
 
         Ok(ImageCandidate {
             path: path.to_path_buf(),
+            camera: format!("{} {}", metadata.make, metadata.model),
             exif: Arc::new(Mutex::new(metadata.exif)),
         })
     }
 
+    pub fn capture_time(&self) -> Option<NaiveDateTime> {
+        capture_time(&self.exif.lock().unwrap())
+    }
+
+    pub fn iso(&self) -> Option<u32> {
+        let exif = self.exif.lock().unwrap();
+        exif.iso_speed_ratings.map(|x| x as u32).or(exif.iso_speed)
+    }
+
+    pub fn exposure_seconds(&self) -> Option<f32> {
+        self.exif.lock().unwrap().exposure_time.map(|x| x.as_f32())
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.exif.lock().unwrap().serial_number.clone()
+    }
+
     pub fn json(self) -> serde_json::Value {
         let exif = self.exif.lock().unwrap();
 
@@ -55,3 +75,15 @@ impl ImageCandidate {
         })
     }
 }
+
+/// Returns the time the image was captured, including the sub-second time if available.
+pub fn capture_time(exif: &Exif) -> Option<NaiveDateTime> {
+    let time = NaiveDateTime::parse_from_str(exif.date_time_original.as_ref()?.as_str(), "%Y:%m:%d %H:%M:%S").ok()?;
+    let sub_seconds = exif
+        .sub_sec_time_original
+        .as_ref()
+        .and_then(|x| format!("0.{}", x.trim()).parse::<f64>().ok())
+        .unwrap_or_default();
+
+    Some(time + Duration::microseconds((sub_seconds * 1e6) as i64))
+}
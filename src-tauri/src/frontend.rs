@@ -1,8 +1,10 @@
 use crate::fileinfo;
 use crate::fileinfo::ImageCandidate;
 use crate::processing;
+use crate::processing::comets::{CometTiming, Comets};
+use crate::processing::stacking::StackingMode;
 use crate::processing::status::{InfoLoadingStatus, ProcessingStatus};
-use crate::processing::RenderedPreview;
+use crate::processing::{CalibrationFiles, MergeSettings, RenderedPreview};
 use log::{error, info};
 
 use std::fs;
@@ -50,40 +52,72 @@ pub async fn load_image_infos(
 pub async fn run_merge(
     window: tauri::Window,
     lightframes: Vec<String>,
-    darkframes: Vec<String>,
+    calibration: CalibrationFiles,
     mode_str: String,
+    settings: Option<MergeSettings>,
     out_path: String,
 ) -> Result<serde_json::Value, serde_json::Value> {
+    // Detailed settings take precedence over the basic mode
+    let settings = match settings {
+        Some(settings) => settings,
+        None => MergeSettings {
+            comets: mode_str.parse::<Comets>().anyhow_to_json()?,
+            comet_timing: CometTiming::Index,
+            gap_filling: None,
+            sequence: None,
+            stacking: StackingMode::Maximum,
+            kappa: processing::default_kappa(),
+            brightest_count: processing::default_brightest_count(),
+            median_samples: processing::default_median_samples(),
+            sky_mask: None,
+            composite: None,
+            fade_sky_only: false,
+            transients: None,
+            keep_meteors: None,
+            rejection: None,
+            save_masters: None,
+            dark_library: None,
+            dark_scaling: None,
+            memory_budget: None,
+            strips: None,
+            checkpoint: None,
+            append_to: None,
+        },
+    };
+    info!("Running merge in '{}' mode with {:?}", mode_str, settings);
+
+    let paths_light: Vec<PathBuf> = lightframes.iter().map(|x| Path::new(x).to_path_buf()).collect();
+    let (calibration, dark_match) =
+        processing::match_dark_library(&paths_light, calibration, &settings).anyhow_to_json()?;
+
     let state = ProcessingStatus::new(
         lightframes.len(),
-        darkframes.len(),
+        &calibration.counts(),
+        settings.passes(),
         String::from("processing_state_change"),
         Some(window),
     );
 
-    let paths_light = lightframes.into_iter().map(|x| Path::new(&x).to_path_buf()).collect();
-    let paths_dark = darkframes.into_iter().map(|x| Path::new(&x).to_path_buf()).collect();
-
-    let mode = match mode_str.as_str() {
-        "falling" => processing::Comets::Falling,
-        "raising" => processing::Comets::Raising,
-        _ => processing::Comets::Normal,
-    };
-    info!("Running merge in '{}' mode", mode_str);
-
     let start = Instant::now();
-    let image = processing::run_merge(paths_light, paths_dark, mode, state).anyhow_to_json()?;
+    let result = processing::run_merge(paths_light, calibration, settings, state).anyhow_to_json()?;
 
-    let exif = image.exif.clone();
-    let writer = image.get_image_writer().anyhow_to_json()?;
-    writer.write_dng(PathBuf::from(out_path)).anyhow_to_json()?;
+    let exif = result.image.exif.clone();
+    let out_path = PathBuf::from(out_path);
+    // The metadata is written last, such that it never describes a missing or outdated DNG
+    let info = result.info();
+    let writer = result.image.get_image_writer().anyhow_to_json()?;
+    writer.write_dng(out_path.clone()).anyhow_to_json()?;
+    info.save(&out_path).anyhow_to_json()?;
 
     // Render a preview to show in the UI
     let preview_bytes = writer.get_preview_bytes().anyhow_to_json()?;
 
     info!("Processing took {} seconds", start.elapsed().as_secs());
 
-    Ok(json!(RenderedPreview::new(preview_bytes, exif)))
+    let mut response = json!(RenderedPreview::new(preview_bytes, exif));
+    response["report"] = json!(result.report);
+    response["dark_match"] = json!(dark_match);
+    Ok(response)
 }
 
 fn fetch_exif(path: PathBuf) -> anyhow::Result<ImageCandidate> {
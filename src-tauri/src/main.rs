@@ -14,12 +14,23 @@ mod processing;
 
 use crate::processing::status::ProcessingStatus;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::{Args, Parser, Subcommand};
 
+use anyhow::Context;
 use log::info;
-use processing::Comets;
+use processing::checkpoint::{self, Checkpointing, MergeInputs};
+use processing::comets::{CometTiming, Comets};
+use processing::dark_library::{self, DarkLibrary};
+use processing::dark_scaling::DarkScaling;
+use processing::frame_quality::{self, FrameRejection, RejectionAction};
+use processing::meteors::MeteorScan;
+use processing::sequence::{SequenceExport, SequenceFormat};
+use processing::sky_mask::{ForegroundMode, MaskSource, SkyMaskSettings};
+use processing::stacking::StackingMode;
+use processing::transients::{self, StreakDetection};
+use processing::{CalibrationFiles, MergeReport, MergeResult, MergeSettings};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -29,9 +40,14 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Runs the merge on the CLI
     Merge(Merge),
+    /// Scans the lightframes for meteors and reports the candidates
+    Meteors(Meteors),
+    /// Combines the intermediate files of partial merges into the final result
+    Combine(Combine),
 }
 
 #[derive(Args)]
@@ -40,22 +56,260 @@ struct Merge {
     files: Vec<PathBuf>,
 
     /// Save the resulting DNG file in this path
+    #[arg(short, long, required_unless_present = "partial_out")]
+    out: Option<PathBuf>,
+
+    /// Save the uncalibrated merge at full precision as an intermediate file in this path, which is combined with the
+    /// partial merges of the other files of the sequence later
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["out", "preview"])]
+    partial_out: Option<PathBuf>,
+
+    /// Darkframe, shot with the same settings as the lightframes, to reduce the noise. Can be repeated. All types of
+    /// calibration frames also accept the masters saved by an earlier run
+    #[arg(long = "dark", value_name = "FILE")]
+    darks: Vec<PathBuf>,
+
+    /// Bias frame, shot with the shortest exposure, to separate the readout offset from the darkframes. Can be
+    /// repeated
+    #[arg(long = "bias", value_name = "FILE")]
+    bias: Vec<PathBuf>,
+
+    /// Flat frame of an evenly lit surface, shot with the same optics as the lightframes, to correct the vignetting.
+    /// Can be repeated
+    #[arg(long = "flat", value_name = "FILE")]
+    flats: Vec<PathBuf>,
+
+    /// Darkframe with the exposure of the flat frames. Can be repeated
+    #[arg(long = "dark-flat", value_name = "FILE")]
+    dark_flats: Vec<PathBuf>,
+
+    /// Save the averaged calibration frames as masters with their metadata into this directory
+    #[arg(long, value_name = "DIR")]
+    save_masters: Option<PathBuf>,
+
+    /// Directory of darkframes and master darks, from which the best match for the lightframes is chosen if no
+    /// darkframes are given. The camera, ISO and exposure time are matched, but not the sensor temperature
+    #[arg(long, value_name = "DIR")]
+    dark_library: Option<PathBuf>,
+
+    /// Difference of the dark current in stops, above which the best match of the dark library is reported as poor
+    #[arg(long, value_name = "STOPS", default_value_t = dark_library::default_max_mismatch())]
+    max_dark_mismatch: f32,
+
+    /// Scale the darkframe to the lightframes by the ratio of the exposure times, by minimising the residual noise,
+    /// or both
+    #[arg(long, value_name = "SCALING")]
+    dark_scaling: Option<DarkScaling>,
+
+    /// Approximate memory for the frames that are merged at once, limiting the number of decoded frames
+    #[arg(long, value_name = "MIB")]
+    memory_budget: Option<usize>,
+
+    /// Merge the lightframes in this number of horizontal strips, keeping only a strip of every frame in memory. Every
+    /// frame is decoded once per strip, which multiplies the loading time by the number of strips. The memory budget
+    /// limits the number of frames that are decoded at full size at once
+    #[arg(long, value_name = "COUNT", requires = "memory_budget")]
+    strips: Option<usize>,
+
+    /// Save the merged frames into this directory every few files, and continue from the checkpoint there if the
+    /// same merge was interrupted
+    #[arg(long, value_name = "DIR", conflicts_with = "resume")]
+    checkpoint: Option<PathBuf>,
+
+    /// Number of files that are merged between two checkpoints
+    #[arg(long, value_name = "FILES", default_value_t = checkpoint::default_interval())]
+    checkpoint_interval: usize,
+
+    /// Continue the merge of the checkpoint in this directory with its files and settings
+    #[arg(long, value_name = "DIR")]
+    resume: Option<PathBuf>,
+
+    /// Merge the files into an earlier result: a DNG of star trails, or a checkpoint directory or intermediate file
+    /// that keeps the full precision for all stacking modes and the calibration frames
+    #[arg(long, value_name = "FILE|DIR")]
+    append_to: Option<PathBuf>,
+
+    /// Save the preview JPEG of the result in this path
     #[arg(short, long)]
-    out: PathBuf,
+    preview: Option<PathBuf>,
+
+    /// The mode for merging: 'normal', 'falling' or 'raising', optionally followed by a curve and a minimum
+    /// intensity, like 'falling:exponential=3:floor=0.1'. Curves are 'linear', 'exponential=<rate>',
+    /// 'sigmoid=<steepness>' and 'length=<frames>'. Arbitrary curves are given as points of position and
+    /// intensity, like 'sampled:0=1,0.5=0.3,1=0'. A rolling comet tail that fades over the last frames is given
+    /// like 'tail:length=30'.
+    #[arg(short, long, required_unless_present = "resume")]
+    mode: Option<Comets>,
+
+    /// Whether the comet intensity follows the order of the files or their capture times
+    #[arg(short, long, default_value = "index")]
+    timing: CometTiming,
+
+    /// Fill gaps of up to this many pixels between the trails of consecutive lightframes, with maximum stacking only
+    #[arg(short, long, value_name = "PIXELS")]
+    gap_filling: Option<usize>,
+
+    /// Export the build-up of the star trails after every lightframe as numbered files into this directory
+    #[arg(short, long, value_name = "DIR")]
+    sequence: Option<PathBuf>,
+
+    /// File formats of the exported sequence
+    #[arg(long, value_delimiter = ',', default_value = "dng")]
+    sequence_formats: Vec<SequenceFormat>,
+
+    /// How the lightframes are stacked: star trails by maximum, noise-reduced by one of the averaging modes, or by
+    /// the order statistics of every pixel
+    #[arg(long, default_value = "maximum")]
+    stacking: StackingMode,
+
+    /// Number of standard deviations around the mean that are accepted by the clipping modes
+    #[arg(long, default_value_t = processing::default_kappa())]
+    kappa: f32,
+
+    /// Number of brightest values per pixel that are averaged by the brightest mode
+    #[arg(long, value_name = "K", default_value_t = processing::default_brightest_count())]
+    brightest: usize,
+
+    /// Number of values per pixel the median is estimated from. Higher values are more exact but need more memory
+    #[arg(long, value_name = "SAMPLES", default_value_t = processing::default_median_samples())]
+    median_samples: usize,
+
+    /// Image at the raw resolution, where white marks the sky and black the foreground
+    #[arg(long, value_name = "FILE", conflicts_with = "detect_mask")]
+    mask: Option<PathBuf>,
+
+    /// Detects the sky from the movement of the stars instead of using a mask file
+    #[arg(long)]
+    detect_mask: bool,
+
+    /// Radius in pixels over which the sky and the foreground of the mask blend into each other
+    #[arg(long, value_name = "PIXELS", default_value_t = 0)]
+    feather: usize,
+
+    /// Saves the sky mask as an image, e.g. to correct a detected mask by hand
+    #[arg(long, value_name = "FILE")]
+    export_mask: Option<PathBuf>,
+
+    /// Stacks the foreground of the mask with the given mode and keeps the star trails only in the sky
+    #[arg(long, value_name = "FOREGROUND", alias = "foreground")]
+    composite: Option<ForegroundMode>,
+
+    /// Fades the comets only in the sky of the mask, merging the foreground at full brightness
+    #[arg(long)]
+    fade_sky_only: bool,
+
+    /// Replaces the streaks of satellites and planes by the values of the neighbouring frames
+    #[arg(long)]
+    remove_transients: bool,
+
+    /// Number of noise standard deviations by which a streak has to exceed the neighbouring frames
+    #[arg(long, default_value_t = transients::default_threshold())]
+    transient_threshold: f32,
+
+    /// Minimum length of a streak in pixels, which has to exceed the trail of a star within one frame
+    #[arg(long, value_name = "PIXELS", default_value_t = transients::default_min_length())]
+    transient_length: usize,
+
+    /// Meteor report, whose lightframes are merged without removing transients
+    #[arg(long, value_name = "REPORT")]
+    keep_meteors: Option<PathBuf>,
+
+    /// Finds lightframes that deviate from the preceding ones, like frames with clouds, headlights or flashes
+    #[arg(long)]
+    reject_frames: bool,
+
+    /// Whether deviating lightframes are left out of the merge or only reported
+    #[arg(long, default_value = "reject")]
+    rejection_action: RejectionAction,
+
+    /// Maximum relative change of the mean brightness compared to the preceding lightframes
+    #[arg(long, value_name = "FRACTION", default_value_t = frame_quality::default_max_brightness_change())]
+    max_brightness_change: f32,
+
+    /// Maximum relative change of the sky background compared to the preceding lightframes
+    #[arg(long, value_name = "FRACTION", default_value_t = frame_quality::default_max_background_change())]
+    max_background_change: f32,
+
+    /// Minimum fraction of the stars of the preceding lightframes that have to be visible
+    #[arg(long, value_name = "FRACTION", default_value_t = frame_quality::default_min_star_fraction())]
+    min_star_fraction: f32,
+
+    /// Number of preceding lightframes the deviations are measured against
+    #[arg(long, value_name = "FRAMES", default_value_t = frame_quality::default_baseline_frames())]
+    baseline_frames: usize,
+}
+
+#[derive(Args)]
+struct Combine {
+    /// Intermediate files of the partial merges, in the order of the sequence
+    files: Vec<PathBuf>,
+
+    /// Save the resulting DNG file in this path
+    #[arg(short, long, required_unless_present = "partial_out")]
+    out: Option<PathBuf>,
+
+    /// Save the combination as a new intermediate file in this path, to combine it with further files later
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["out", "preview"])]
+    partial_out: Option<PathBuf>,
 
     /// Save the preview JPEG of the result in this path
     #[arg(short, long)]
     preview: Option<PathBuf>,
+}
 
-    /// The mode for merging
+#[derive(Args)]
+struct Meteors {
+    /// RAW input files to scan
+    files: Vec<PathBuf>,
+
+    /// Save the report in this path, as CSV if it ends with '.csv' and as JSON otherwise
     #[arg(short, long)]
-    mode: Comets,
+    report: PathBuf,
+
+    /// Save cropped previews of the candidates in this directory
+    #[arg(short, long, value_name = "DIR")]
+    previews: Option<PathBuf>,
+
+    /// Number of noise standard deviations by which a meteor has to exceed the neighbouring frames
+    #[arg(long, default_value_t = transients::default_threshold())]
+    threshold: f32,
+
+    /// Minimum length of a meteor in pixels
+    #[arg(long, value_name = "PIXELS", default_value_t = transients::default_min_length())]
+    min_length: usize,
 }
 
 fn program_description() -> String {
     format!("{} v{}", env!("CARGO_PKG_NAME"), version!())
 }
 
+fn print_report(report: &MergeReport) {
+    if let Some(scale) = report.dark_scale {
+        println!("Scaled the darkframe by {:.3}", scale);
+    }
+    for touched in &report.transients {
+        println!("Removed {} transient streaks from {:#?}", touched.streaks, touched.path);
+    }
+    for flagged in &report.flagged {
+        let action = if flagged.rejected { "Rejected" } else { "Flagged" };
+        println!("{} {:#?}: {}", action, flagged.path, flagged.reasons.join(", "));
+    }
+}
+
+fn write_result(result: MergeResult, out: &Path, preview: &Option<PathBuf>) -> anyhow::Result<()> {
+    // The metadata is written last, such that it never describes a missing or outdated DNG
+    let info = result.info();
+    let writer = result.image.get_image_writer()?;
+    writer.write_dng(out.to_path_buf())?;
+    info.save(out)?;
+
+    if let Some(x) = preview {
+        writer.write_preview_jpg(x.to_path_buf())?;
+    }
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     pretty_env_logger::init();
     let cli = Cli::parse();
@@ -64,15 +318,134 @@ fn main() -> anyhow::Result<()> {
 
     match &cli.command {
         Some(Commands::Merge(cmd)) => {
-            let state = ProcessingStatus::new(cmd.files.len(), 0, String::from("processing_state_change"), None);
-            let image = processing::run_merge(cmd.files.clone(), vec![], cmd.mode, state)?;
+            let inputs = match &cmd.resume {
+                Some(directory) => MergeInputs::resume(directory, cmd.checkpoint_interval)?,
+                None => {
+                    let mask_source = match (&cmd.mask, cmd.detect_mask) {
+                        (Some(path), _) => Some(MaskSource::File(path.clone())),
+                        (None, true) => Some(MaskSource::Detect),
+                        (None, false) => None,
+                    };
+                    let settings = MergeSettings {
+                        comets: cmd.mode.clone().context("The mode of the merge is missing")?,
+                        comet_timing: cmd.timing,
+                        gap_filling: cmd.gap_filling,
+                        sequence: cmd.sequence.clone().map(|directory| SequenceExport {
+                            directory,
+                            formats: cmd.sequence_formats.clone(),
+                        }),
+                        stacking: cmd.stacking,
+                        kappa: cmd.kappa,
+                        brightest_count: cmd.brightest,
+                        median_samples: cmd.median_samples,
+                        sky_mask: mask_source.map(|source| SkyMaskSettings {
+                            source,
+                            feather: cmd.feather,
+                            export: cmd.export_mask.clone(),
+                        }),
+                        composite: cmd.composite,
+                        fade_sky_only: cmd.fade_sky_only,
+                        transients: cmd.remove_transients.then_some(StreakDetection {
+                            threshold: cmd.transient_threshold,
+                            min_length: cmd.transient_length,
+                        }),
+                        keep_meteors: cmd.keep_meteors.clone(),
+                        rejection: cmd.reject_frames.then_some(FrameRejection {
+                            action: cmd.rejection_action,
+                            max_brightness_change: cmd.max_brightness_change,
+                            max_background_change: cmd.max_background_change,
+                            min_star_fraction: cmd.min_star_fraction,
+                            baseline_frames: cmd.baseline_frames,
+                        }),
+                        save_masters: cmd.save_masters.clone(),
+                        dark_library: cmd.dark_library.clone().map(|directory| DarkLibrary {
+                            directory,
+                            max_mismatch: cmd.max_dark_mismatch,
+                        }),
+                        dark_scaling: cmd.dark_scaling,
+                        memory_budget: cmd.memory_budget,
+                        strips: cmd.strips,
+                        checkpoint: cmd.checkpoint.clone().map(|directory| Checkpointing {
+                            directory,
+                            interval: cmd.checkpoint_interval,
+                        }),
+                        append_to: cmd.append_to.clone(),
+                    };
+                    let calibration = CalibrationFiles {
+                        darkframes: cmd.darks.clone(),
+                        bias: cmd.bias.clone(),
+                        flats: cmd.flats.clone(),
+                        dark_flats: cmd.dark_flats.clone(),
+                    };
+                    let (calibration, dark_match) = processing::match_dark_library(&cmd.files, calibration, &settings)?;
+                    if let Some(dark_match) = &dark_match {
+                        println!("Using {} darkframes of the library", dark_match.files.len());
+                        for warning in &dark_match.warnings {
+                            println!("Warning: The darkframes of the library have {}", warning);
+                        }
+                    }
+
+                    MergeInputs {
+                        lightframes: cmd.files.clone(),
+                        calibration,
+                        settings,
+                    }
+                }
+            };
 
-            let writer = image.get_image_writer()?;
-            writer.write_dng(cmd.out.clone())?;
+            let state = ProcessingStatus::new(
+                inputs.lightframes.len(),
+                &inputs.calibration.counts(),
+                inputs.settings.passes(),
+                String::from("processing_state_change"),
+                None,
+            );
 
-            if let Some(x) = &cmd.preview {
-                writer.write_preview_jpg(x.to_path_buf())?;
+            if let Some(path) = &cmd.partial_out {
+                let report = processing::run_partial_merge(
+                    inputs.lightframes,
+                    inputs.calibration,
+                    inputs.settings,
+                    path,
+                    state,
+                )?;
+                print_report(&report);
+                println!("Saved the partial merge to {:#?}", path);
+                return Ok(());
             }
+
+            let result = processing::run_merge(inputs.lightframes, inputs.calibration, inputs.settings, state)?;
+            print_report(&result.report);
+            write_result(result, cmd.out.as_ref().context("The output file is missing")?, &cmd.preview)?;
+        }
+        Some(Commands::Combine(cmd)) => {
+            let state = ProcessingStatus::new(0, &[], 1, String::from("processing_state_change"), None);
+
+            match &cmd.partial_out {
+                Some(path) => {
+                    processing::run_partial_combine(&cmd.files, path, state)?;
+                    println!("Saved the combined partial merges to {:#?}", path);
+                }
+                None => {
+                    let result = processing::run_combine(&cmd.files, state)?;
+                    print_report(&result.report);
+                    write_result(result, cmd.out.as_ref().context("The output file is missing")?, &cmd.preview)?;
+                }
+            }
+        }
+        Some(Commands::Meteors(cmd)) => {
+            let scan = MeteorScan {
+                detection: StreakDetection {
+                    threshold: cmd.threshold,
+                    min_length: cmd.min_length,
+                },
+                report: cmd.report.clone(),
+                previews: cmd.previews.clone(),
+            };
+            let state = ProcessingStatus::scanning(cmd.files.len(), String::from("processing_state_change"), None);
+            let candidates = processing::scan_meteors(cmd.files.clone(), scan, state)?;
+
+            println!("Found {} meteor candidates", candidates.len());
         }
         None => {
             info!("Called without parameters. Starting GUI");
@@ -1,37 +1,271 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time;
 
 use anyhow::{self, Context};
 use base64::{engine::general_purpose as b64, Engine as _};
-use clap::ValueEnum;
-use log::info;
+use chrono::NaiveDateTime;
+use log::{info, warn};
 use rawler::exif::Exif;
 use rayon::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use crate::fileinfo::ImageCandidate;
+use crate::processing::accumulator::Accumulator;
+use crate::processing::append::ResultInfo;
+use crate::processing::checkpoint::{Checkpointing, MergeInputs};
+use crate::processing::comets::{CometTiming, Comets};
+use crate::processing::dark_library::{DarkLibrary, DarkMatch};
+use crate::processing::dark_scaling::DarkScaling;
+use crate::processing::frame_quality::{Baseline, FlaggedFrame, FrameRejection, FrameStatistics};
 use crate::processing::image::{Frame, Image};
+use crate::processing::masters::CalibrationType;
+use crate::processing::meteors::{MeteorCandidate, MeteorScan};
+use crate::processing::order_statistics::{OrderStack, OrderStatistic};
+use crate::processing::sequence::SequenceExport;
+use crate::processing::sky_mask::{ForegroundMode, MaskSource, SkyMask, SkyMaskSettings};
+use crate::processing::stacking::{ClippingBounds, Stack, StackingMode, SumStack};
+use crate::processing::transients::{StreakDetection, TransientReport};
 
+mod accumulator;
+pub mod append;
+mod black_level;
+pub mod checkpoint;
 pub mod cli_progress;
+pub mod comets;
+pub mod dark_library;
+pub mod dark_scaling;
 mod dng_writing;
+pub mod frame_quality;
+mod gap_filling;
 mod image;
+pub mod intermediate;
+pub mod masters;
+pub mod meteors;
+mod order_statistics;
+mod scheduler;
+pub mod sequence;
+pub mod sky_mask;
+pub mod stacking;
 pub mod status;
+pub mod transients;
 
-#[derive(Copy, Clone, ValueEnum)]
-pub enum Comets {
-    Falling,
-    Raising,
-    Normal,
+/// Settings that control how the lightframes are merged
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MergeSettings {
+    pub comets: Comets,
+    #[serde(default)]
+    pub comet_timing: CometTiming,
+    /// Maximum gap in pixels to fill between the trails of consecutive lightframes
+    #[serde(default)]
+    pub gap_filling: Option<usize>,
+    /// Exports every intermediate state of the star trails
+    #[serde(default)]
+    pub sequence: Option<SequenceExport>,
+    #[serde(default)]
+    pub stacking: StackingMode,
+    /// Number of standard deviations around the mean that are accepted by the clipping modes
+    #[serde(default = "default_kappa")]
+    pub kappa: f32,
+    /// Number of brightest values that are averaged by the brightest mode
+    #[serde(default = "default_brightest_count")]
+    pub brightest_count: usize,
+    /// Number of values per pixel the median is estimated from, which bounds the memory consumption
+    #[serde(default = "default_median_samples")]
+    pub median_samples: usize,
+    /// Separates the sky from the foreground
+    #[serde(default)]
+    pub sky_mask: Option<SkyMaskSettings>,
+    /// Stacks the foreground with the given mode and keeps the star trails only in the sky
+    #[serde(default)]
+    pub composite: Option<ForegroundMode>,
+    /// Restricts the comet fading to the sky, such that the foreground is merged at full brightness
+    #[serde(default)]
+    pub fade_sky_only: bool,
+    /// Replaces the streaks of satellites and planes by the neighbouring frames
+    #[serde(default)]
+    pub transients: Option<StreakDetection>,
+    /// Meteor report, whose lightframes are merged without removing transients
+    #[serde(default)]
+    pub keep_meteors: Option<PathBuf>,
+    /// Leaves out or flags lightframes that deviate from the preceding ones
+    #[serde(default)]
+    pub rejection: Option<FrameRejection>,
+    /// Saves the averaged calibration frames into this directory, to load them in later runs
+    #[serde(default)]
+    pub save_masters: Option<PathBuf>,
+    /// Chooses the darkframes from this library, if none are given
+    #[serde(default)]
+    pub dark_library: Option<DarkLibrary>,
+    /// Scales the darkframe to the lightframes, e.g. if its exposure time or temperature differs
+    #[serde(default)]
+    pub dark_scaling: Option<DarkScaling>,
+    /// Approximate memory in MiB for the frames that are merged at once, unbounded by default
+    #[serde(default)]
+    pub memory_budget: Option<usize>,
+    /// Merges the lightframes in this number of horizontal strips one after another, such that only a strip of every
+    /// frame is kept in memory
+    #[serde(default)]
+    pub strips: Option<usize>,
+    /// Saves the merged frames periodically and continues from the last checkpoint of the same merge
+    #[serde(default)]
+    pub checkpoint: Option<Checkpointing>,
+    /// Earlier result that the files are merged into, either a DNG of star trails or a checkpoint directory
+    #[serde(default)]
+    pub append_to: Option<PathBuf>,
 }
 
+impl MergeSettings {
+    /// Number of times the lightframes have to be loaded
+    pub fn passes(&self) -> usize {
+        let detection = match &self.sky_mask {
+            Some(SkyMaskSettings {
+                source: MaskSource::Detect,
+                ..
+            }) => 1,
+            _ => 0,
+        };
+
+        // Every strip loads the lightframes again
+        self.stacking.passes() * self.strips.unwrap_or(1).max(1) + detection
+    }
+}
+
+pub fn default_kappa() -> f32 {
+    2.5
+}
+
+pub fn default_brightest_count() -> usize {
+    3
+}
+
+pub fn default_median_samples() -> usize {
+    15
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 enum FrameType {
-    Lightframe(usize),
+    Lightframe,
     Darkframe,
+    /// Frame with the shortest exposure, which only holds the readout offset of the sensor
+    Bias,
+    /// Frame of an evenly lit surface, which shows the vignetting of the optics
+    Flat,
+    /// Darkframe with the exposure of the flat frames
+    DarkFlat,
+}
+
+impl FrameType {
+    fn calibration_type(&self) -> Option<CalibrationType> {
+        match self {
+            FrameType::Lightframe => None,
+            FrameType::Darkframe => Some(CalibrationType::Dark),
+            FrameType::Bias => Some(CalibrationType::Bias),
+            FrameType::Flat => Some(CalibrationType::Flat),
+            FrameType::DarkFlat => Some(CalibrationType::DarkFlat),
+        }
+    }
+}
+
+/// Determines how a loaded lightframe enters the merge
+enum LightStacking {
+    Maximum {
+        keep_boundaries: bool,
+    },
+    Sum,
+    Statistics,
+    Clipped(Arc<ClippingBounds>),
+    /// Keeps the maximum and the statistics side by side to detect the sky
+    Segmentation,
+    Order {
+        statistic: OrderStatistic,
+        median_samples: usize,
+    },
+    Composite {
+        keep_boundaries: bool,
+        foreground: ForegroundMode,
+        median_samples: usize,
+        mask: Arc<SkyMask>,
+    },
 }
 
 struct LoadTask {
     frame_type: FrameType,
     path: PathBuf,
+    intensity: Intensity,
+    /// Factor by which the merged lightframes fade before this one is merged, to keep the comet head of an exported
+    /// sequence at its newest frame
+    fade: f32,
+    /// Restricts the intensity to the sky
+    sky: Option<Arc<SkyMask>>,
+    /// Keeps only a strip of the frame
+    strip: Option<Strip>,
+}
+
+/// Rows of a frame that are kept after decoding
+#[derive(Clone)]
+struct Strip {
+    rows: Range<usize>,
+    /// Height of the whole frame, which is decoded before the strip is cut out
+    height: usize,
+}
+
+/// Brightness of a loaded frame for the comet fading
+#[derive(Clone)]
+enum Intensity {
+    Fixed(f32),
+    /// Follows the capture time of the decoded lightframe within the span of the sequence
+    Captured(Arc<CaptureSpan>),
+}
+
+/// Capture times of the first and the last lightframe, between which the comet curve is spread
+struct CaptureSpan {
+    comets: Comets,
+    first: NaiveDateTime,
+    /// Microseconds between the first and the last lightframe
+    span: i64,
+    count: usize,
+}
+
+impl CaptureSpan {
+    /// Positions the lightframe by its capture time, scaled to the frame count, such that interruptions in the
+    /// sequence don't cause jumps in the intensity of the comets.
+    fn intensity(&self, image: &Image) -> Option<f32> {
+        let offset = (image.capture_time()? - self.first).num_microseconds().unwrap_or(0);
+        let position = offset as f32 / self.span as f32 * (self.count - 1) as f32;
+
+        Some(self.comets.intensity(position, self.count))
+    }
+}
+
+/// Details about the merged lightframes
+#[derive(Debug, Default, Serialize)]
+pub struct MergeReport {
+    pub transients: Vec<TransientReport>,
+    /// Lightframes that deviate from the baseline, whether they were rejected or only flagged
+    pub flagged: Vec<FlaggedFrame>,
+    /// Factor by which the darkframe was scaled to the lightframes
+    pub dark_scale: Option<f32>,
+}
+
+pub struct MergeResult {
+    pub image: Image,
+    pub report: MergeReport,
+    /// Lightframes of the result, including those of an earlier result that the files were appended to
+    pub sources: Vec<PathBuf>,
+}
+
+impl MergeResult {
+    /// Metadata to store next to the DNG, such that further files can be appended to it.
+    pub fn info(&self) -> ResultInfo {
+        ResultInfo {
+            frame_count: self.image.num_images(),
+            sources: self.sources.clone(),
+            dark_scale: self.report.dark_scale,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -62,12 +296,163 @@ impl RenderedPreview {
     }
 }
 
+/// Calibration frames, which are averaged into a master frame per type.
+///
+/// Masters that were saved by an earlier run can be given instead of or in addition to the single frames.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalibrationFiles {
+    #[serde(default)]
+    pub darkframes: Vec<PathBuf>,
+    #[serde(default)]
+    pub bias: Vec<PathBuf>,
+    #[serde(default)]
+    pub flats: Vec<PathBuf>,
+    #[serde(default)]
+    pub dark_flats: Vec<PathBuf>,
+}
+
+impl CalibrationFiles {
+    /// Number of files of every type, as each type is merged separately
+    pub fn counts(&self) -> Vec<usize> {
+        self.by_type().iter().map(|(_, files)| files.len()).collect()
+    }
+
+    fn by_type(&self) -> [(FrameType, &Vec<PathBuf>); 4] {
+        [
+            (FrameType::Darkframe, &self.darkframes),
+            (FrameType::Bias, &self.bias),
+            (FrameType::Flat, &self.flats),
+            (FrameType::DarkFlat, &self.dark_flats),
+        ]
+    }
+}
+
+/// Adds the darkframes of the library that match the first lightframe best, if no darkframes are given.
+///
+/// This has to happen before the processing starts, as the darkframes count into the progress.
+pub fn match_dark_library(
+    lightframe_files: &[PathBuf],
+    calibration: CalibrationFiles,
+    settings: &MergeSettings,
+) -> anyhow::Result<(CalibrationFiles, Option<DarkMatch>)> {
+    let (library, lightframe) = match (&settings.dark_library, lightframe_files.first()) {
+        (Some(library), Some(lightframe)) if calibration.darkframes.is_empty() => (library, lightframe),
+        _ => return Ok((calibration, None)),
+    };
+
+    let dark_match = library.find_match(lightframe)?;
+    let darkframes = dark_match.as_ref().map(|x| x.files.clone()).unwrap_or_default();
+
+    Ok((
+        CalibrationFiles {
+            darkframes,
+            ..calibration
+        },
+        dark_match,
+    ))
+}
+
 pub fn run_merge(
     lightframe_files: Vec<PathBuf>,
-    darkframe_files: Vec<PathBuf>,
-    mode: Comets,
+    calibration: CalibrationFiles,
+    settings: MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<MergeResult> {
+    let mut report = MergeReport::default();
+    let frame = merge_files(&lightframe_files, &calibration, &settings, state.clone(), &mut report);
+
+    let merged = frame.and_then(|frame| match &settings.append_to {
+        Some(base) => append::append(base, frame, &settings, state.clone()),
+        None => {
+            let (image, dark_scale) = frame.get_image(settings.dark_scaling)?;
+            Ok((image, dark_scale, Vec::new()))
+        }
+    });
+
+    if merged.is_err() {
+        state.lock().unwrap().abort();
+    }
+
+    let (image, dark_scale, mut sources) = merged?;
+    report.dark_scale = dark_scale;
+    sources.extend(lightframe_files);
+
+    Ok(MergeResult { image, report, sources })
+}
+
+/// Merges the files without calibrating the result and writes the frame into an intermediate file, which is combined
+/// with other partial merges of the same sequence later.
+pub fn run_partial_merge(
+    lightframe_files: Vec<PathBuf>,
+    calibration: CalibrationFiles,
+    settings: MergeSettings,
+    path: &Path,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<MergeReport> {
+    let mut report = MergeReport::default();
+    let written = intermediate::ensure_partial(&settings)
+        .and_then(|_| merge_files(&lightframe_files, &calibration, &settings, state.clone(), &mut report))
+        .and_then(|frame| {
+            let inputs = MergeInputs {
+                lightframes: lightframe_files,
+                calibration,
+                settings,
+            };
+            intermediate::write(path, &frame, &inputs)
+        });
+
+    if written.is_err() {
+        state.lock().unwrap().abort();
+    }
+
+    written?;
+    Ok(report)
+}
+
+/// Combines the intermediate files of partial merges in the order of the sequence into the final result.
+pub fn run_combine(files: &[PathBuf], state: Arc<Mutex<status::ProcessingStatus>>) -> anyhow::Result<MergeResult> {
+    let combined = intermediate::combine(files, state.clone()).and_then(|(frame, inputs)| {
+        let (image, dark_scale) = frame.get_image(inputs.settings.dark_scaling)?;
+        Ok((image, dark_scale, inputs.lightframes))
+    });
+
+    if combined.is_err() {
+        state.lock().unwrap().abort();
+    }
+
+    let (image, dark_scale, sources) = combined?;
+    let report = MergeReport {
+        dark_scale,
+        ..MergeReport::default()
+    };
+
+    Ok(MergeResult { image, report, sources })
+}
+
+/// Combines the intermediate files into a new intermediate file, such that partial merges are reduced in steps.
+pub fn run_partial_combine(
+    files: &[PathBuf],
+    path: &Path,
     state: Arc<Mutex<status::ProcessingStatus>>,
-) -> anyhow::Result<Image> {
+) -> anyhow::Result<()> {
+    let written = intermediate::combine(files, state.clone())
+        .and_then(|(frame, inputs)| intermediate::write(path, &frame, &inputs));
+
+    if written.is_err() {
+        state.lock().unwrap().abort();
+    }
+
+    written
+}
+
+/// Loads and merges the lightframes and calibration frames into a frame, which isn't calibrated yet.
+fn merge_files(
+    lightframe_files: &[PathBuf],
+    calibration: &CalibrationFiles,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+    report: &mut MergeReport,
+) -> anyhow::Result<Box<Frame>> {
     let num_threads = num_cpus::get();
     info!(
         "System has {} cores and {} threads. Using {} worker threads.",
@@ -76,64 +461,878 @@ pub fn run_merge(
         num_threads
     );
 
+    // Determine the position of each lightframe in the sequence for the comet intensity
+    let positions: Vec<f32> = (0..lightframe_files.len()).map(|i| i as f32).collect();
+    let span = match settings.comet_timing {
+        CometTiming::Index => None,
+        CometTiming::Timestamp => capture_span(lightframe_files, &settings.comets)?,
+    };
+
     // Create loading tasks for lightframes
     let mut tasks: Vec<LoadTask> = lightframe_files
         .iter()
         .enumerate()
         .map(|(i, p)| LoadTask {
-            frame_type: FrameType::Lightframe(i),
+            frame_type: FrameType::Lightframe,
             path: p.to_path_buf(),
+            intensity: match &span {
+                Some(span) => Intensity::Captured(span.clone()),
+                None => Intensity::Fixed(settings.comets.intensity(positions[i], lightframe_files.len())),
+            },
+            fade: 1.0,
+            sky: None,
+            strip: None,
         })
         .collect();
 
-    // Add darkframes to the tasklist
-    tasks.append(
-        &mut darkframe_files
+    // Every exported output applies the comet over its own lightframes, which the trails of the maximum mode follow by
+    // fading. The outputs are positioned by index, as every output needs the positions of its frames up front.
+    if settings.sequence.is_some() && settings.stacking == StackingMode::Maximum {
+        if let Some(fading) = settings.comets.sequence_fading(&positions) {
+            for (task, (intensity, fade)) in tasks.iter_mut().zip(fading) {
+                task.intensity = Intensity::Fixed(intensity);
+                task.fade = fade;
+            }
+        }
+    }
+
+    // Add the calibration frames to the tasklist
+    for (frame_type, files) in calibration.by_type() {
+        tasks.extend(files.iter().map(|p| LoadTask {
+            frame_type,
+            path: p.to_path_buf(),
+            intensity: Intensity::Fixed(1.0),
+            fade: 1.0,
+            sky: None,
+            strip: None,
+        }));
+    }
+
+    // Loading and merging
+    let frame = merge_frames(&tasks, settings, state, report)?;
+    if let Some(directory) = &settings.save_masters {
+        save_masters(&frame, directory)?;
+    }
+
+    Ok(frame)
+}
+
+fn save_masters(frame: &Frame, directory: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(directory).with_context(|| format!("Could not create directory {:#?}", directory))?;
+
+    for (calibration_type, master) in frame.masters() {
+        let path = masters::save(directory, calibration_type, master)?;
+        info!("Saved master of type {:?} to {:#?}", calibration_type, path);
+    }
+
+    Ok(())
+}
+
+fn merge_frames(
+    tasks: &[LoadTask],
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+    report: &mut MergeReport,
+) -> anyhow::Result<Box<Frame>> {
+    if let Some(strips) = settings.strips.filter(|x| *x > 1) {
+        return merge_strips(tasks, strips, settings, state, report);
+    }
+
+    let mask = sky_mask(tasks, settings, state.clone())?;
+
+    // Comets fade only in the sky, while the foreground is merged at full brightness
+    let sky = match (settings.fade_sky_only, &mask) {
+        (true, Some(mask)) => Some(mask.clone()),
+        (true, None) => anyhow::bail!("Fading only the sky needs a sky mask"),
+        (false, _) => None,
+    };
+    let tasks: Vec<LoadTask> = tasks
+        .iter()
+        .map(|t| LoadTask {
+            frame_type: t.frame_type,
+            path: t.path.clone(),
+            intensity: t.intensity.clone(),
+            fade: t.fade,
+            sky: sky.clone(),
+            strip: None,
+        })
+        .collect();
+
+    let stacking = match (settings.stacking, settings.composite) {
+        (StackingMode::Maximum, Some(foreground)) => LightStacking::Composite {
+            keep_boundaries: settings.gap_filling.is_some(),
+            foreground,
+            median_samples: settings.median_samples,
+            mask: mask.clone().context("Composites need a sky mask")?,
+        },
+        (_, Some(_)) => anyhow::bail!("Composites need the maximum stacking mode for the sky"),
+        (StackingMode::Maximum, None) => LightStacking::Maximum {
+            keep_boundaries: settings.gap_filling.is_some(),
+        },
+        // The gaps are only filled between the trails of a maximum
+        (_, None) if settings.gap_filling.is_some() => anyhow::bail!("Gap filling needs the maximum stacking mode"),
+        (StackingMode::Mean, None) => LightStacking::Sum,
+        (StackingMode::Median, None) => LightStacking::Order {
+            statistic: OrderStatistic::Median,
+            median_samples: settings.median_samples,
+        },
+        (StackingMode::Minimum, None) => LightStacking::Order {
+            statistic: OrderStatistic::Minimum,
+            median_samples: settings.median_samples,
+        },
+        (StackingMode::Brightest, None) => LightStacking::Order {
+            statistic: OrderStatistic::Brightest(settings.brightest_count),
+            median_samples: settings.median_samples,
+        },
+        (mode, None) => {
+            // A first pass over the lightframes determines the accepted range of every pixel
+            info!("Collecting statistics of the lightframes for {:?} stacking...", mode);
+            let lights: Vec<&LoadTask> = tasks
+                .iter()
+                .filter(|t| matches!(t.frame_type, FrameType::Lightframe))
+                .collect();
+            let statistics =
+                match merge_tasks(&lights, &LightStacking::Statistics, settings, state.clone())?.take_stack() {
+                    Some(Stack::Sum(x)) => x,
+                    _ => anyhow::bail!("No lightframes to stack"),
+                };
+
+            LightStacking::Clipped(Arc::new(statistics.clipping_bounds(settings.kappa, mode)?))
+        }
+    };
+
+    let tasks: Vec<&LoadTask> = tasks.iter().collect();
+    match (&settings.sequence, &settings.transients, &settings.rejection, &settings.checkpoint) {
+        (None, None, None, Some(checkpointing)) => {
+            // A restored frame gets the mask that the loaded frames would carry
+            let frame_mask = match settings.fade_sky_only || settings.composite.is_some() {
+                true => mask,
+                false => None,
+            };
+            merge_checkpointed(&tasks, &stacking, settings, checkpointing, frame_mask, state)
+        }
+        (None, None, None, None) => merge_tasks(&tasks, &stacking, settings, state),
+        (None, None, Some(rejection), None) => merge_rejecting(&tasks, &stacking, settings, rejection, state, report),
+        (_, _, _, Some(_)) => {
+            anyhow::bail!("Checkpoints can't be combined with sequences, transient removal or frame rejection")
+        }
+        _ => merge_ordered(&tasks, &stacking, settings, state, report),
+    }
+}
+
+/// Merges the lightframes strip by strip, where only the current strip of every frame is kept after decoding.
+///
+/// The calibration frames are averaged at full size, as the flat frame is normalised over the whole frame.
+fn merge_strips(
+    tasks: &[LoadTask],
+    strips: usize,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+    report: &mut MergeReport,
+) -> anyhow::Result<Box<Frame>> {
+    anyhow::ensure!(
+        settings.gap_filling.is_none() && settings.sky_mask.is_none(),
+        "Merging in strips can't be combined with gap filling or sky masks, which need the whole frame"
+    );
+    anyhow::ensure!(
+        settings.sequence.is_none() && settings.transients.is_none() && settings.rejection.is_none(),
+        "Merging in strips can't be combined with sequences, transient removal or frame rejection"
+    );
+    anyhow::ensure!(settings.checkpoint.is_none(), "Merging in strips can't be combined with checkpoints");
+    anyhow::ensure!(
+        settings.memory_budget.is_some(),
+        "Merging in strips needs a memory budget, which limits the number of frames that are decoded at once"
+    );
+
+    let (lights, calibration): (Vec<&LoadTask>, Vec<&LoadTask>) = tasks
+        .iter()
+        .partition(|t| matches!(t.frame_type, FrameType::Lightframe));
+    let first = lights.first().context("No lightframes to merge")?;
+
+    // The stacking applies only to the lightframes
+    let stacking = LightStacking::Maximum { keep_boundaries: false };
+    let calibration = merge_tasks(&calibration, &stacking, settings, state.clone())?;
+
+    let strip_settings = MergeSettings {
+        strips: None,
+        ..settings.clone()
+    };
+    let strip_rows = Image::strip_rows(&first.path, strips)?;
+    let height = strip_rows.last().map_or(0, |x| x.end);
+    let mut merged = Vec::new();
+    for rows in strip_rows {
+        info!("Merging rows {} to {} of the lightframes...", rows.start, rows.end);
+        let strip_tasks: Vec<LoadTask> = lights
             .iter()
-            .map(|p| LoadTask {
-                frame_type: FrameType::Darkframe,
-                path: p.to_path_buf(),
+            .map(|t| LoadTask {
+                frame_type: t.frame_type,
+                path: t.path.clone(),
+                intensity: t.intensity.clone(),
+                fade: t.fade,
+                sky: None,
+                strip: Some(Strip {
+                    rows: rows.clone(),
+                    height,
+                }),
             })
-            .collect(),
-    );
+            .collect();
 
-    // Loading and merging
-    let frame = tasks
-        .par_iter()
-        .map(|t| load_image(t, mode, state.clone()))
-        .reduce(|| Ok(Box::new(Frame::identity())), |x, y| x?.merge(*y?, state.clone()));
+        merged.push(*merge_frames(&strip_tasks, &strip_settings, state.clone(), report)?);
+    }
 
-    if frame.is_err() {
-        state.lock().unwrap().abort();
+    // The strips keep their stacks, such that the result merges like the frame of a single merge
+    let lightframes = Frame::join_strips(merged)?;
+    Box::new(lightframes).merge(*calibration, settings, state)
+}
+
+/// Loads or detects the sky mask, if one is requested.
+fn sky_mask(
+    tasks: &[LoadTask],
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<Option<Arc<SkyMask>>> {
+    let mask_settings = match &settings.sky_mask {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let mask = match &mask_settings.source {
+        MaskSource::File(path) => {
+            let mask = SkyMask::load(path)?;
+
+            // Fail before the merge instead of at the first frame that is blended with the mask
+            if let Some(task) = tasks.iter().find(|t| matches!(t.frame_type, FrameType::Lightframe)) {
+                Image::ensure_file_mask_size(&task.path, &mask)?;
+            }
+            mask
+        }
+        MaskSource::Detect => {
+            info!("Detecting the sky in the lightframes...");
+
+            // The detection works on the unchanged lightframes, without comet fading
+            let lights: Vec<LoadTask> = tasks
+                .iter()
+                .filter(|t| matches!(t.frame_type, FrameType::Lightframe))
+                .map(|t| LoadTask {
+                    frame_type: FrameType::Lightframe,
+                    path: t.path.clone(),
+                    intensity: Intensity::Fixed(1.0),
+                    fade: 1.0,
+                    sky: None,
+                    strip: None,
+                })
+                .collect();
+            let detection_settings = MergeSettings {
+                comets: Comets::Normal,
+                ..settings.clone()
+            };
+
+            let lights: Vec<&LoadTask> = lights.iter().collect();
+            merge_tasks(&lights, &LightStacking::Segmentation, &detection_settings, state)?.detect_sky()?
+        }
+    };
+
+    if let Some(path) = &mask_settings.export {
+        mask.save(path)?;
+    }
+
+    Ok(Some(Arc::new(mask.feathered(mask_settings.feather))))
+}
+
+fn merge_tasks(
+    tasks: &[&LoadTask],
+    stacking: &LightStacking,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<Box<Frame>> {
+    let load = |t: &&LoadTask| load_image(t, stacking, state.clone());
+    let merge = |x: Box<Frame>, y: Box<Frame>| x.merge(*y, settings, state.clone());
+
+    // A strip is cut out of the whole decoded frame
+    let decoded_ratio = match tasks.first().and_then(|t| t.strip.as_ref()) {
+        Some(strip) => strip.height as f64 / strip.rows.len().max(1) as f64,
+        None => 1.0,
+    };
+
+    match settings.memory_budget {
+        Some(budget) => {
+            let size = |x: &Frame| x.memory_size();
+            let frame = scheduler::merge_streaming(
+                tasks,
+                budget << 20,
+                load,
+                merge,
+                |x| size(x),
+                |x| (size(x) as f64 * decoded_ratio) as usize,
+            )?;
+            Ok(frame.unwrap_or_else(|| Box::new(Frame::identity())))
+        }
+        None => tasks
+            .par_iter()
+            .map(load)
+            .reduce(|| Ok(Box::new(Frame::identity())), |x, y| merge(x?, y?)),
+    }
+}
+
+/// Merges the tasks in batches of the checkpoint interval and saves the merged frame after every batch.
+///
+/// The batches are merged in their order, such that the saved frame holds the first files of the merge. A checkpoint of
+/// the same merge is continued, leaving out the files it includes.
+fn merge_checkpointed(
+    tasks: &[&LoadTask],
+    stacking: &LightStacking,
+    settings: &MergeSettings,
+    checkpointing: &Checkpointing,
+    mask: Option<Arc<SkyMask>>,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<Box<Frame>> {
+    let directory = &checkpointing.directory;
+    std::fs::create_dir_all(directory).with_context(|| format!("Could not create directory {:#?}", directory))?;
+
+    let inputs = merge_inputs(tasks, settings);
+    let files: Vec<&Path> = tasks.iter().map(|t| t.path.as_path()).collect();
+    let bounds = match stacking {
+        LightStacking::Clipped(bounds) => Some(bounds),
+        _ => None,
+    };
+
+    let (mut frame, mut included) = match checkpointing.restore(&inputs, &files)? {
+        Some(manifest) => {
+            let frame = Frame::load(&manifest.state_directory(directory), &manifest.frame, bounds, mask)?;
+            skip_included(&tasks[..manifest.included.len()], state.clone());
+            (Box::new(frame), manifest.included)
+        }
+        None => (Box::new(Frame::identity()), Vec::new()),
+    };
+
+    for batch in tasks[included.len()..].chunks(checkpointing.interval.max(1)) {
+        let merged = merge_tasks(batch, stacking, settings, state.clone())?;
+        frame = frame.merge(*merged, settings, state.clone())?;
+
+        included.extend(batch.iter().map(|t| t.path.clone()));
+        checkpointing.save(&frame, &inputs, &included)?;
+    }
+
+    Ok(frame)
+}
+
+/// Files of the merge by type, as a checkpoint records them.
+fn merge_inputs(tasks: &[&LoadTask], settings: &MergeSettings) -> MergeInputs {
+    let mut inputs = MergeInputs {
+        lightframes: Vec::new(),
+        calibration: CalibrationFiles::default(),
+        settings: settings.clone(),
+    };
+
+    for task in tasks {
+        let files = match task.frame_type {
+            FrameType::Lightframe => &mut inputs.lightframes,
+            FrameType::Darkframe => &mut inputs.calibration.darkframes,
+            FrameType::Bias => &mut inputs.calibration.bias,
+            FrameType::Flat => &mut inputs.calibration.flats,
+            FrameType::DarkFlat => &mut inputs.calibration.dark_flats,
+        };
+        files.push(task.path.clone());
+    }
+
+    inputs
+}
+
+/// Counts the loading and merging of the files in a checkpoint as done.
+fn skip_included(tasks: &[&LoadTask], state: Arc<Mutex<status::ProcessingStatus>>) {
+    // Every type of frame is merged separately, such that it takes one merge less than its number of files
+    let types: HashSet<FrameType> = tasks.iter().map(|t| t.frame_type).collect();
+
+    let state = state.lock().unwrap();
+    tasks.iter().for_each(|_| state.skip_loading());
+    (types.len()..tasks.len()).for_each(|_| state.skip_merging());
+}
+
+/// Merges the lightframes one after another, comparing every frame with its neighbours to remove transients, with
+/// the preceding frames to reject bad ones and exporting the result after every frame.
+///
+/// The calibration frames are merged first, such that every exported image is calibrated. Lightframes are loaded in
+/// parallel chunks to limit the number of frames that are kept in memory.
+fn merge_ordered(
+    tasks: &[&LoadTask],
+    stacking: &LightStacking,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+    report: &mut MergeReport,
+) -> anyhow::Result<Box<Frame>> {
+    if let Some(sequence) = &settings.sequence {
+        std::fs::create_dir_all(&sequence.directory)
+            .with_context(|| format!("Could not create directory {:#?}", sequence.directory))?;
+    }
+
+    let (lights, calibration): (Vec<&LoadTask>, Vec<&LoadTask>) = tasks
+        .iter()
+        .partition(|t| matches!(t.frame_type, FrameType::Lightframe));
+
+    let mut frame = merge_tasks(&calibration, stacking, settings, state.clone())?;
+
+    // Frames listed in a meteor report are merged unchanged, to keep the meteors
+    let keep: HashSet<PathBuf> = match &settings.keep_meteors {
+        Some(path) => meteors::read_report(path)?.into_iter().collect(),
+        None => HashSet::new(),
+    };
+
+    // The removal of transients needs the frames before and after the current one
+    let mut loader = OrderedLoader::new(&lights, settings.transients.is_some(), settings.memory_budget, state.clone());
+    let clean = |task: &LoadTask, image: &Image, before: Option<&Image>, after: Option<&Image>| {
+        let cleaned = match &settings.transients {
+            Some(detection) if !keep.contains(&task.path) => image.remove_transients(before, after, detection)?,
+            _ => None,
+        };
+
+        // The statistics are measured without the removed streaks, which would otherwise count as brightness
+        let statistics = match &settings.rejection {
+            Some(_) => Some(cleaned.as_ref().map(|(x, _)| x).unwrap_or(image).statistics()?),
+            None => None,
+        };
+
+        Ok((cleaned, statistics))
+    };
+
+    let mut baseline = settings.rejection.clone().map(Baseline::new);
+    let mut merged_lights = false;
+    while let Some((count_merged, chunk)) = loader.next_chunk(clean)? {
+        let mut snapshots = Vec::new();
+        for (i, (task, image, (cleaned, statistics))) in chunk.into_iter().enumerate() {
+            let image = match cleaned {
+                Some((image, streaks)) => {
+                    info!("Removed {} transient streaks from {:#?}", streaks, task.path);
+                    report.transients.push(TransientReport {
+                        path: task.path.clone(),
+                        streaks,
+                    });
+                    image
+                }
+                None => image,
+            };
+
+            let rejected = match (&mut baseline, statistics) {
+                (Some(baseline), Some(statistics)) => check_frame(baseline, task, statistics, report),
+                _ => false,
+            };
+
+            // Every lightframe but the first one counts as a merge, also if it is rejected or merged into a frame
+            // without lightframes
+            let first = count_merged + i == 0;
+            if !first && (rejected || !merged_lights) {
+                state.lock().unwrap().skip_merging();
+            }
+            if !rejected {
+                frame = Box::new(frame.fade(task.fade, settings)?);
+                frame = frame.merge(*build_frame(task, attenuate(task, image)?, stacking)?, settings, state.clone())?;
+                merged_lights = true;
+            }
+
+            // Rejected frames repeat the previous state, such that the sequence stays aligned with the lightframes
+            if settings.sequence.is_some() && merged_lights {
+                snapshots.push((count_merged + i, frame.snapshot(settings.dark_scaling)?));
+            }
+        }
+
+        if let Some(sequence) = &settings.sequence {
+            snapshots
+                .into_par_iter()
+                .try_for_each(|(index, image)| sequence.write(index, image))?;
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Merges the lightframes in parallel chunks, leaving out the ones that deviate from the baseline of the preceding
+/// frames.
+///
+/// The statistics are measured while loading, such that only the comparison with the baseline follows the order of the
+/// sequence. With a memory budget, the first chunk holds a single frame, which measures the size of the further chunks.
+fn merge_rejecting(
+    tasks: &[&LoadTask],
+    stacking: &LightStacking,
+    settings: &MergeSettings,
+    rejection: &FrameRejection,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+    report: &mut MergeReport,
+) -> anyhow::Result<Box<Frame>> {
+    let (lights, calibration): (Vec<&LoadTask>, Vec<&LoadTask>) = tasks
+        .iter()
+        .partition(|t| matches!(t.frame_type, FrameType::Lightframe));
+
+    let mut frame = merge_tasks(&calibration, stacking, settings, state.clone())?;
+    let mut baseline = Baseline::new(rejection.clone());
+    let mut merged_lights = false;
+
+    let mut size = initial_chunk_size(settings.memory_budget);
+    let mut start = 0;
+    while start < lights.len() {
+        let chunk = &lights[start..(start + size).min(lights.len())];
+        let loaded = chunk
+            .par_iter()
+            .map(|t| load_measured(t, stacking, state.clone()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if let (Some(budget), Some((light, _))) = (settings.memory_budget, loaded.first()) {
+            size = chunk_size(budget, light.memory_size());
+        }
+
+        let mut accepted = Vec::new();
+        for (j, (task, (light, statistics))) in chunk.iter().zip(loaded).enumerate() {
+            let rejected = check_frame(&mut baseline, task, statistics, report);
+
+            // Every lightframe but the first one counts as a merge, also if it is rejected or merged into a frame
+            // without lightframes
+            let first = start + j == 0;
+            if !first && (rejected || !merged_lights) {
+                state.lock().unwrap().skip_merging();
+            }
+            if !rejected {
+                accepted.push(light);
+                merged_lights = true;
+            }
+        }
+
+        let merged = accepted
+            .into_par_iter()
+            .map(Ok)
+            .reduce(|| Ok(Box::new(Frame::identity())), |x, y| x?.merge(*y?, settings, state.clone()))?;
+        frame = frame.merge(*merged, settings, state.clone())?;
+        start += chunk.len();
+    }
+
+    Ok(frame)
+}
+
+/// Number of frames in the first chunk of an ordered merge, which is a single frame to measure the size of a frame if
+/// the memory is limited.
+fn initial_chunk_size(memory_budget: Option<usize>) -> usize {
+    match memory_budget {
+        Some(_) => 1,
+        None => num_cpus::get(),
     }
+}
+
+/// Number of frames in a chunk of an ordered merge, such that the decoded frames stay within the budget in MiB.
+///
+/// A chunk is decoded and merged in parallel, which holds about as many frames per loaded one as a worker of a
+/// streaming merge.
+fn chunk_size(memory_budget: usize, frame_size: usize) -> usize {
+    scheduler::Schedule::new(memory_budget << 20, frame_size, frame_size, num_cpus::get()).workers
+}
 
-    frame?.get_image()
+/// Compares the statistics of a lightframe with the baseline and lists deviating frames in the report.
+///
+/// Returns whether the frame is left out of the merge.
+fn check_frame(
+    baseline: &mut Baseline,
+    task: &LoadTask,
+    statistics: FrameStatistics,
+    report: &mut MergeReport,
+) -> bool {
+    let reasons = baseline.check(statistics);
+    let rejected = !reasons.is_empty() && baseline.rejects();
+    if !reasons.is_empty() {
+        warn!("Lightframe {:#?} deviates: {}", task.path, reasons.join(", "));
+        report.flagged.push(FlaggedFrame {
+            path: task.path.clone(),
+            statistics,
+            reasons,
+            rejected,
+        });
+    }
+    rejected
+}
+
+/// Scans the lightframes for meteors and writes a report with the candidates.
+///
+/// Meteors are detected like the streaks of satellites, as they only appear in a single frame.
+pub fn scan_meteors(
+    lightframe_files: Vec<PathBuf>,
+    scan: MeteorScan,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<Vec<MeteorCandidate>> {
+    if let Some(directory) = &scan.previews {
+        std::fs::create_dir_all(directory).with_context(|| format!("Could not create directory {:#?}", directory))?;
+    }
+
+    let tasks: Vec<LoadTask> = lightframe_files
+        .iter()
+        .map(|p| LoadTask {
+            frame_type: FrameType::Lightframe,
+            path: p.to_path_buf(),
+            intensity: Intensity::Fixed(1.0),
+            fade: 1.0,
+            sky: None,
+            strip: None,
+        })
+        .collect();
+    let lights: Vec<&LoadTask> = tasks.iter().collect();
+
+    let mut candidates = Vec::new();
+    let mut loader = OrderedLoader::new(&lights, true, None, state.clone());
+    let detect = |_: &LoadTask, image: &Image, before: Option<&Image>, after: Option<&Image>| {
+        image.find_streaks(before, after, &scan.detection)
+    };
+
+    while let Some((_, chunk)) = loader.next_chunk(detect)? {
+        for (task, image, streaks) in chunk {
+            state.lock().unwrap().finish_scanning();
+            if streaks.regions.is_empty() {
+                continue;
+            }
+            info!("Found {} meteor candidates in {:#?}", streaks.regions.len(), task.path);
+
+            candidates.extend(streaks.regions.iter().map(|region| MeteorCandidate {
+                path: task.path.clone(),
+                timestamp: image.capture_time(),
+                bounding_box: *region,
+            }));
+
+            if let Some(directory) = &scan.previews {
+                let writer = image.get_image_writer()?;
+                let stem = task.path.file_stem().unwrap_or_default().to_string_lossy();
+                for (i, region) in streaks.regions.iter().enumerate() {
+                    writer.write_preview_crop(directory.join(format!("{}_{}.jpg", stem, i)), region, 100)?;
+                }
+            }
+        }
+    }
+
+    meteors::write_report(&candidates, &scan.report)?;
+    Ok(candidates)
+}
+
+/// Loads the lightframes in parallel chunks, keeping the neighbours of the current chunk in memory.
+struct OrderedLoader<'a> {
+    lights: &'a [&'a LoadTask],
+    neighbours: bool,
+    /// Memory budget in MiB, which limits the size of the chunks after the first one
+    memory_budget: Option<usize>,
+    chunk_size: usize,
+    /// Last frame of the previous chunk
+    previous: Option<Image>,
+    /// Loaded frames, starting with the first one of the next chunk
+    pending: Vec<Image>,
+    count_processed: usize,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+}
+
+impl<'a> OrderedLoader<'a> {
+    fn new(
+        lights: &'a [&'a LoadTask],
+        neighbours: bool,
+        memory_budget: Option<usize>,
+        state: Arc<Mutex<status::ProcessingStatus>>,
+    ) -> Self {
+        OrderedLoader {
+            lights,
+            neighbours,
+            memory_budget,
+            chunk_size: initial_chunk_size(memory_budget),
+            previous: None,
+            pending: Vec::new(),
+            count_processed: 0,
+            state,
+        }
+    }
+
+    /// Loads the next chunk and processes its frames in parallel, passing the preceding and following frame if the
+    /// neighbours are kept.
+    ///
+    /// Returns the index of the first frame of the chunk and the processed frames in their order.
+    #[allow(clippy::type_complexity)]
+    fn next_chunk<T: Send>(
+        &mut self,
+        process: impl Fn(&LoadTask, &Image, Option<&Image>, Option<&Image>) -> anyhow::Result<T> + Sync,
+    ) -> anyhow::Result<Option<(usize, Vec<(&'a LoadTask, Image, T)>)>> {
+        let first = self.count_processed;
+        let size = self.chunk_size.min(self.lights.len() - first);
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let end = (first + size + self.neighbours as usize).min(self.lights.len());
+        let state = self.state.clone();
+        self.pending.append(
+            &mut self.lights[first + self.pending.len()..end]
+                .par_iter()
+                .map(|t| decode(t, state.clone()))
+                .collect::<anyhow::Result<Vec<Image>>>()?,
+        );
+        if let (Some(budget), Some(image)) = (self.memory_budget, self.pending.first()) {
+            self.chunk_size = chunk_size(budget, image.memory_size());
+        }
+
+        let chunk = &self.lights[first..first + size];
+        let (pending, previous) = (&self.pending, &self.previous);
+        let results = chunk
+            .par_iter()
+            .enumerate()
+            .map(|(i, task)| {
+                if self.neighbours {
+                    let before = if i == 0 { previous.as_ref() } else { pending.get(i - 1) };
+                    process(task, &pending[i], before, pending.get(i + 1))
+                } else {
+                    process(task, &pending[i], None, None)
+                }
+            })
+            .collect::<anyhow::Result<Vec<T>>>()?;
+
+        if self.neighbours {
+            self.previous = Some(self.pending[size - 1].clone());
+        }
+        self.count_processed += size;
+
+        let processed = chunk
+            .iter()
+            .copied()
+            .zip(self.pending.drain(..size))
+            .zip(results)
+            .map(|((task, image), result)| (task, image, result))
+            .collect();
+
+        Ok(Some((first, processed)))
+    }
+}
+
+/// Reads the capture times of the first and the last lightframe, between which the other frames are positioned once
+/// they are decoded.
+///
+/// A single lightframe or frames without a span of capture times are positioned by their index instead.
+fn capture_span(lightframe_files: &[PathBuf], comets: &Comets) -> anyhow::Result<Option<Arc<CaptureSpan>>> {
+    let (first, last) = match (lightframe_files.first(), lightframe_files.last()) {
+        (Some(first), Some(last)) if lightframe_files.len() > 1 => (first, last),
+        _ => return Ok(None),
+    };
+    let capture_time = |path: &PathBuf| {
+        ImageCandidate::load(path)?
+            .capture_time()
+            .with_context(|| format!("File {:#?} has no capture time", path))
+    };
+
+    let first = capture_time(first)?;
+    let span = (capture_time(last)? - first).num_microseconds().unwrap_or(i64::MAX);
+    if span <= 0 {
+        warn!("The lightframes span no capture time, positioning the comets by the order of the files");
+        return Ok(None);
+    }
+
+    Ok(Some(Arc::new(CaptureSpan {
+        comets: comets.clone(),
+        first,
+        span,
+        count: lightframe_files.len(),
+    })))
 }
 
 fn load_image(
     task: &LoadTask,
-    comets: Comets,
+    stacking: &LightStacking,
     state: Arc<Mutex<status::ProcessingStatus>>,
 ) -> anyhow::Result<Box<Frame>> {
-    let count_lights = state.lock().unwrap().count_lights;
-    let intensity = match task.frame_type {
-        FrameType::Lightframe(index) => match comets {
-            Comets::Falling => 1.0 - index as f32 / count_lights as f32,
-            Comets::Raising => index as f32 / count_lights as f32,
-            Comets::Normal => 1.0,
-        },
-        FrameType::Darkframe => 1.0,
+    let img = load_raw(task, state)?;
+    build_frame(task, img, stacking)
+}
+
+/// Loads a lightframe and measures its statistics before the comets fade it.
+fn load_measured(
+    task: &LoadTask,
+    stacking: &LightStacking,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<(Box<Frame>, FrameStatistics)> {
+    let img = decode(task, state)?;
+    let statistics = img.statistics()?;
+    Ok((build_frame(task, attenuate(task, img)?, stacking)?, statistics))
+}
+
+fn load_raw(task: &LoadTask, state: Arc<Mutex<status::ProcessingStatus>>) -> anyhow::Result<Image> {
+    let img = match &task.strip {
+        Some(strip) => decode(task, state)?.strip(strip.rows.clone())?,
+        None => decode(task, state)?,
     };
+    attenuate(task, img)
+}
 
+/// Loads the image data at full brightness, restoring the frame count of calibration masters.
+fn decode(task: &LoadTask, state: Arc<Mutex<status::ProcessingStatus>>) -> anyhow::Result<Image> {
     state.lock().unwrap().start_loading();
-    let img = Image::from_raw_file(task.path.as_path(), intensity)
+    let img = Image::from_raw_file(task.path.as_path(), 1.0)
         .with_context(|| format!("Could not load file {:#?}", task.path))?;
     state.lock().unwrap().finish_loading();
 
-    let frame = match task.frame_type {
-        FrameType::Lightframe(_) => Frame::from_lightframe(img),
-        FrameType::Darkframe => Frame::from_darkframe(img),
+    match task.frame_type.calibration_type() {
+        Some(calibration_type) => masters::restore(img, &task.path, calibration_type),
+        None => Ok(img),
+    }
+}
+
+/// Applies the comet intensity of the task, restricted to the sky if there is one.
+fn attenuate(task: &LoadTask, img: Image) -> anyhow::Result<Image> {
+    let intensity = match &task.intensity {
+        Intensity::Fixed(intensity) => *intensity,
+        Intensity::Captured(span) => span
+            .intensity(&img)
+            .with_context(|| format!("File {:#?} has no capture time", task.path))?,
+    };
+
+    match &task.sky {
+        Some(sky) => img.attenuate_sky(intensity, sky),
+        None => Ok(img.attenuate(intensity)),
+    }
+}
+
+/// Wraps a loaded image into a frame that merges according to the stacking.
+fn build_frame(task: &LoadTask, img: Image, stacking: &LightStacking) -> anyhow::Result<Box<Frame>> {
+    let frame = match (&task.frame_type, stacking) {
+        (FrameType::Lightframe, LightStacking::Maximum { keep_boundaries: true }) => {
+            Frame::from_lightframe(img).keep_boundaries()
+        }
+        (FrameType::Lightframe, LightStacking::Maximum { .. }) => Frame::from_lightframe(img),
+        (FrameType::Lightframe, LightStacking::Sum) => Frame::from_stack(Stack::Sum(SumStack::from_image(img, false)?)),
+        (FrameType::Lightframe, LightStacking::Statistics) => {
+            Frame::from_stack(Stack::Sum(SumStack::from_image(img, true)?))
+        }
+        (FrameType::Lightframe, LightStacking::Clipped(bounds)) => {
+            Frame::from_stack(Stack::Sum(SumStack::clipped(img, bounds)?))
+        }
+        (
+            FrameType::Lightframe,
+            LightStacking::Order {
+                statistic,
+                median_samples,
+            },
+        ) => Frame::from_stack(Stack::Order(OrderStack::from_image(img, *statistic, *median_samples)?)),
+        (FrameType::Lightframe, LightStacking::Segmentation) => {
+            Frame::composite(img.clone(), Stack::Sum(SumStack::from_image(img, true)?), None)
+        }
+        (
+            FrameType::Lightframe,
+            LightStacking::Composite {
+                keep_boundaries,
+                foreground,
+                median_samples,
+                mask,
+            },
+        ) => {
+            let foreground = match foreground {
+                ForegroundMode::Mean => Stack::Sum(SumStack::from_image(img.clone(), false)?),
+                ForegroundMode::Median => {
+                    Stack::Order(OrderStack::from_image(img.clone(), OrderStatistic::Median, *median_samples)?)
+                }
+            };
+            let frame = Frame::composite(img, foreground, Some(mask.clone()));
+            if *keep_boundaries {
+                frame.keep_boundaries()
+            } else {
+                frame
+            }
+        }
+        (FrameType::Darkframe, _) => Frame::from_darkframe(Accumulator::from_image(img)?),
+        (FrameType::Bias, _) => Frame::from_bias(Accumulator::from_image(img)?),
+        (FrameType::Flat, _) => Frame::from_flat(Accumulator::from_image(img)?),
+        (FrameType::DarkFlat, _) => Frame::from_dark_flat(Accumulator::from_image(img)?),
     };
 
-    Ok(Box::new(frame))
+    Ok(Box::new(frame.with_mask(task.sky.clone())))
 }
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::processing::checkpoint::{self, ImagePart};
+use crate::processing::image::{Image, Mergable, MergeMode};
+
+/// Per-sample sums of averaged frames, which are rounded to image data only once all frames are merged.
+///
+/// Every frame is weighted by the number of images it holds, such that a master counts like its single frames. As
+/// the sums are integers, the average doesn't depend on the order in which the frames are merged.
+#[derive(Clone)]
+pub struct Accumulator {
+    /// Metadata of the averaged frames without image data
+    template: Image,
+    sums: Vec<u32>,
+}
+
+impl Accumulator {
+    pub fn from_image(image: Image) -> anyhow::Result<Accumulator> {
+        let weight = image.num_images().max(1) as u32;
+        let sums = image
+            .image_data()?
+            .par_iter()
+            .map(|x| (*x as u32).saturating_mul(weight))
+            .collect();
+
+        Ok(Accumulator {
+            sums,
+            template: image.with_data(Vec::new()),
+        })
+    }
+
+    pub fn merge(self, other: Accumulator) -> anyhow::Result<Accumulator> {
+        anyhow::ensure!(self.sums.len() == other.sums.len(), "Images to average have different dimensions");
+
+        Ok(Accumulator {
+            sums: self
+                .sums
+                .par_iter()
+                .zip(other.sums.par_iter())
+                .map(|(x, y)| x.saturating_add(*y))
+                .collect(),
+            template: self.template.merge(other.template, MergeMode::WeightedAverage)?,
+        })
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.sums.len() * std::mem::size_of::<u32>()
+    }
+
+    /// Writes the sums next to the current average.
+    pub fn save(&self, directory: &Path, name: &str) -> anyhow::Result<ImagePart> {
+        let part = self.template.save_metadata(directory, name)?;
+        checkpoint::write_samples(&part.path(directory, "sums"), &self.sums)?;
+
+        Ok(part)
+    }
+
+    pub fn load(directory: &Path, part: &ImagePart) -> anyhow::Result<Accumulator> {
+        let template = Image::load(directory, part)?;
+        let sums = checkpoint::read_samples(&part.path(directory, "sums"), template.image_data()?.len())?;
+
+        Ok(Accumulator {
+            sums,
+            template: template.with_data(Vec::new()),
+        })
+    }
+
+    /// Computes the average of every sample.
+    pub fn into_image(self) -> Image {
+        let count = self.template.num_images().max(1) as f64;
+        let data = self
+            .sums
+            .par_iter()
+            .map(|x| (*x as f64 / count).round() as u16)
+            .collect();
+
+        self.template.with_data(data)
+    }
+
+    /// Computes the current average without consuming the sums.
+    pub fn to_image(&self) -> Image {
+        self.clone().into_image()
+    }
+}
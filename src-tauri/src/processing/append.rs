@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::processing::checkpoint::{Manifest, MergeInputs};
+use crate::processing::image::{Frame, Image};
+use crate::processing::intermediate;
+use crate::processing::stacking::StackingMode;
+use crate::processing::{status, CalibrationFiles, MergeSettings};
+
+/// Metadata of a merge result, which is stored next to its DNG to append further frames later
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResultInfo {
+    /// Number of lightframes that were merged into the result
+    pub frame_count: usize,
+    #[serde(default)]
+    pub sources: Vec<PathBuf>,
+    /// Factor by which the darkframe was scaled to the lightframes, if it was scaled
+    #[serde(default)]
+    pub dark_scale: Option<f32>,
+}
+
+impl ResultInfo {
+    /// Loads the metadata of a result, which is `None` for results without metadata.
+    pub fn load(path: &Path) -> anyhow::Result<Option<ResultInfo>> {
+        let sidecar = sidecar_path(path);
+        if !sidecar.is_file() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&sidecar).with_context(|| format!("Could not read {:#?}", sidecar))?;
+        let info = serde_json::from_str(&content).with_context(|| format!("Invalid result metadata {:#?}", sidecar))?;
+
+        Ok(Some(info))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let sidecar = sidecar_path(path);
+        fs::write(&sidecar, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Could not write result metadata {:#?}", sidecar))
+    }
+}
+
+/// Merges the frame of the new files into an earlier result and calibrates it, returning the factor of the dark
+/// scaling and the sources of the earlier result.
+///
+/// Checkpoint directories and intermediate files hold the accumulators at full precision, such that they are merged
+/// before the calibration like in a single run. A DNG is already calibrated, so only the calibrated star trails of the
+/// new files are merged into it.
+pub fn append(
+    base: &Path,
+    frame: Box<Frame>,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<(Image, Option<f32>, Vec<PathBuf>)> {
+    if base.is_dir() || intermediate::is_intermediate(base) {
+        let (base, sources) = load_native(base, settings, state.clone())?;
+        let (image, dark_scale) = merge_counted(base, frame, settings, state)?.get_image(settings.dark_scaling)?;
+        return Ok((image, dark_scale, sources));
+    }
+
+    let (image, dark_scale) = frame.get_image(settings.dark_scaling)?;
+    let (base, sources) = load_dng(base, &image, settings, state.clone())?;
+    let appended = Box::new(Frame::from_lightframe(image));
+    let (image, _) = merge_counted(base, appended, settings, state)?.get_image(None)?;
+
+    Ok((image, dark_scale, sources))
+}
+
+/// Loads the frame of a checkpoint or an intermediate file, which has to be merged with the same settings as the new
+/// files.
+///
+/// Appending combines two parts of the sequence, such that it rejects the same merges as combining intermediate files.
+fn load_native(
+    path: &Path,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<(Box<Frame>, Vec<PathBuf>)> {
+    let appended = MergeSettings {
+        append_to: None,
+        ..settings.clone()
+    };
+    intermediate::ensure_partial(&appended).with_context(|| format!("Can't append to {:#?}", path))?;
+
+    info!("Appending to {:#?}...", path);
+    state.lock().unwrap().add_tasks(1, 0);
+    state.lock().unwrap().start_loading();
+    let (frame, stored, sources) = match path.is_dir() {
+        true => {
+            let manifest = Manifest::load(path)?.with_context(|| format!("There is no checkpoint in {:#?}", path))?;
+            let frame = Frame::load(&manifest.state_directory(path), &manifest.frame, None, None)?;
+            let sources = manifest
+                .included
+                .iter()
+                .filter(|x| manifest.inputs.lightframes.contains(x))
+                .cloned()
+                .collect();
+            (frame, manifest.inputs, sources)
+        }
+        false => {
+            let (frame, header) = intermediate::read(path)?;
+            let sources = header.inputs.lightframes.clone();
+            (frame, header.inputs, sources)
+        }
+    };
+    state.lock().unwrap().finish_loading();
+
+    let inputs = MergeInputs {
+        lightframes: Vec::new(),
+        calibration: CalibrationFiles::default(),
+        settings: appended,
+    };
+    intermediate::ensure_compatible(&stored, &inputs)
+        .with_context(|| format!("{:#?} can't be appended to, as it was merged differently", path))?;
+    Ok((Box::new(frame), sources))
+}
+
+/// Loads a DNG of star trails with its frame count, which is estimated from the total exposure time without metadata.
+fn load_dng(
+    path: &Path,
+    appended: &Image,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<(Box<Frame>, Vec<PathBuf>)> {
+    anyhow::ensure!(
+        settings.stacking == StackingMode::Maximum && settings.composite.is_none(),
+        "Only star trails of the maximum mode can be appended to a DNG, other modes need a checkpoint or an \
+         intermediate file"
+    );
+
+    info!("Appending to {:#?}...", path);
+    state.lock().unwrap().add_tasks(1, 0);
+    state.lock().unwrap().start_loading();
+    let image = Image::from_raw_file(path, 1.0).with_context(|| format!("Could not load file {:#?}", path))?;
+    state.lock().unwrap().finish_loading();
+    anyhow::ensure!(
+        image.camera() == appended.camera() && image.size() == appended.size(),
+        "{:#?} was taken with another camera than the appended files",
+        path
+    );
+
+    let info = match ResultInfo::load(path)? {
+        Some(info) => info,
+        None => {
+            // The exposure time of the result is the sum over its frames
+            let frame_count = match (image.frame_exposure(), appended.frame_exposure()) {
+                (Some(total), Some(single)) if single > 0.0 => (total / single).round().max(1.0) as usize,
+                _ => 1,
+            };
+            warn!("{:#?} has no metadata, estimated {} frames from its exposure time", path, frame_count);
+
+            ResultInfo {
+                frame_count,
+                sources: Vec::new(),
+                dark_scale: None,
+            }
+        }
+    };
+
+    let frame = Frame::from_lightframe(image.with_num_images(info.frame_count));
+    Ok((Box::new(frame), info.sources))
+}
+
+/// Merges the new frame into the base, counting the merges on top of the ones of the new files.
+///
+/// The frames of a comet tail are weighted by their distance to the end of their own merge, such that the tail of the
+/// base fades by the number of frames that follow it.
+pub fn merge_counted(
+    base: Box<Frame>,
+    frame: Box<Frame>,
+    settings: &MergeSettings,
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<Box<Frame>> {
+    state.lock().unwrap().add_tasks(0, base.count_merges(&frame));
+    let base = match settings.comets.tail_decay() {
+        Some(decay) => base.fade(decay.powi(frame.num_images() as i32), settings)?,
+        None => *base,
+    };
+
+    base.merge(*frame, settings, state)
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("trawls.json")
+}
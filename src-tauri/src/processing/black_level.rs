@@ -0,0 +1,80 @@
+use rawler::RawImage;
+
+/// Black level of every sample, which repeats in a pattern from the origin of the raw data
+pub struct BlackLevels {
+    levels: Vec<f32>,
+    width: usize,
+    height: usize,
+    cpp: usize,
+    image_width: usize,
+    image_cpp: usize,
+}
+
+impl BlackLevels {
+    /// Measures the black level per CFA channel in the masked areas of the sensor, which follows drifts of the
+    /// offset, e.g. with the temperature. Sensors without masked areas use the black levels of the metadata.
+    pub fn new(raw_image: &RawImage, data: &[u16]) -> BlackLevels {
+        BlackLevels::measure(raw_image, data).unwrap_or_else(|| BlackLevels::declared(raw_image))
+    }
+
+    /// Black level pattern of the metadata, where an inconsistent pattern is replaced by its average.
+    pub fn declared(raw_image: &RawImage) -> BlackLevels {
+        let blacklevel = &raw_image.blacklevel;
+        let levels: Vec<f32> = blacklevel.levels.iter().map(|x| x.as_f32()).collect();
+        let (width, height, cpp) = (blacklevel.width.max(1), blacklevel.height.max(1), blacklevel.cpp.max(1));
+
+        if levels.len() == width * height * cpp {
+            BlackLevels::pattern(raw_image, levels, width, height, cpp)
+        } else {
+            let average = levels.iter().sum::<f32>() / levels.len().max(1) as f32;
+            BlackLevels::pattern(raw_image, vec![average], 1, 1, 1)
+        }
+    }
+
+    /// Averages the masked areas per CFA channel, which is `None` if a channel has no masked samples.
+    fn measure(raw_image: &RawImage, data: &[u16]) -> Option<BlackLevels> {
+        let (width, height, cpp) = (raw_image.width, raw_image.height, raw_image.cpp);
+        let (cfa_width, cfa_height) = match cpp {
+            1 => (raw_image.cfa.width.max(1), raw_image.cfa.height.max(1)),
+            _ => (1, 1),
+        };
+
+        let mut sums = vec![(0u64, 0usize); cfa_width * cfa_height * cpp];
+        for area in &raw_image.blackareas {
+            for y in area.p.y..(area.p.y + area.d.h).min(height) {
+                for x in area.p.x..(area.p.x + area.d.w).min(width) {
+                    for sample in 0..cpp {
+                        let sum = &mut sums[((y % cfa_height) * cfa_width + x % cfa_width) * cpp + sample];
+                        sum.0 += data[(y * width + x) * cpp + sample] as u64;
+                        sum.1 += 1;
+                    }
+                }
+            }
+        }
+
+        if sums.iter().any(|(_, n)| *n == 0) {
+            return None;
+        }
+
+        let levels = sums.iter().map(|(sum, n)| *sum as f32 / *n as f32).collect();
+        Some(BlackLevels::pattern(raw_image, levels, cfa_width, cfa_height, cpp))
+    }
+
+    fn pattern(raw_image: &RawImage, levels: Vec<f32>, width: usize, height: usize, cpp: usize) -> BlackLevels {
+        BlackLevels {
+            levels,
+            width,
+            height,
+            cpp,
+            image_width: raw_image.width.max(1),
+            image_cpp: raw_image.cpp.max(1),
+        }
+    }
+
+    /// Returns the black level of the sample with the given index in the raw data.
+    pub fn at(&self, i: usize) -> f32 {
+        let (pixel, sample) = (i / self.image_cpp, i % self.image_cpp);
+        let (x, y) = (pixel % self.image_width, pixel / self.image_width);
+        self.levels[((y % self.height) * self.width + x % self.width) * self.cpp + sample % self.cpp]
+    }
+}
@@ -0,0 +1,289 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::image::Frame;
+use crate::processing::order_statistics::OrderStatistic;
+use crate::processing::{CalibrationFiles, MergeSettings};
+
+const MANIFEST: &str = "checkpoint.json";
+const STATE_PREFIX: &str = "state-";
+const VERSION: u32 = 1;
+
+/// Saves the merged frames periodically, such that an interrupted merge continues from the last checkpoint
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpointing {
+    pub directory: PathBuf,
+    /// Number of input files that are merged between two checkpoints
+    #[serde(default = "default_interval")]
+    pub interval: usize,
+}
+
+pub fn default_interval() -> usize {
+    50
+}
+
+/// Files and settings of a merge, which a checkpoint belongs to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MergeInputs {
+    pub lightframes: Vec<PathBuf>,
+    pub calibration: CalibrationFiles,
+    pub settings: MergeSettings,
+}
+
+impl MergeInputs {
+    /// Loads the files and settings of the merge that saved the checkpoint, which continues to save checkpoints into
+    /// the same directory.
+    pub fn resume(directory: &Path, interval: usize) -> anyhow::Result<MergeInputs> {
+        let manifest =
+            Manifest::load(directory)?.with_context(|| format!("There is no checkpoint in {:#?}", directory))?;
+        let settings = MergeSettings {
+            checkpoint: Some(Checkpointing {
+                directory: directory.to_path_buf(),
+                interval,
+            }),
+            ..manifest.inputs.settings
+        };
+
+        Ok(MergeInputs {
+            settings,
+            ..manifest.inputs
+        })
+    }
+
+    /// Settings that change the result, leaving out how the merge is run and where its outputs are saved
+    pub fn fingerprint(&self) -> anyhow::Result<serde_json::Value> {
+        let settings = MergeSettings {
+            checkpoint: None,
+            memory_budget: None,
+            strips: None,
+            save_masters: None,
+            append_to: None,
+            ..self.settings.clone()
+        };
+        Ok(serde_json::to_value(settings)?)
+    }
+}
+
+/// Description of the last checkpoint, stored as JSON in the checkpoint directory
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    #[serde(flatten)]
+    pub inputs: MergeInputs,
+    /// Input files in the order they were merged into the saved frame
+    pub included: Vec<PathBuf>,
+    /// Subdirectory with the parts of the frame
+    pub state: String,
+    pub frame: FrameParts,
+}
+
+impl Manifest {
+    /// Loads the manifest of the directory, which is `None` if no checkpoint was written yet.
+    pub fn load(directory: &Path) -> anyhow::Result<Option<Manifest>> {
+        let path = directory.join(MANIFEST);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path).with_context(|| format!("Could not read checkpoint {:#?}", path))?;
+        let manifest: Manifest =
+            serde_json::from_str(&content).with_context(|| format!("Invalid checkpoint {:#?}", path))?;
+        anyhow::ensure!(
+            manifest.version == VERSION,
+            "Checkpoint {:#?} has the unsupported version {}",
+            path,
+            manifest.version
+        );
+
+        Ok(Some(manifest))
+    }
+
+    pub fn state_directory(&self, directory: &Path) -> PathBuf {
+        directory.join(&self.state)
+    }
+}
+
+impl Checkpointing {
+    /// Loads the last checkpoint, if the directory holds one of a merge with the same files and settings.
+    ///
+    /// The included files have to be the first ones of the merge, as the order matters for gap filling and comet
+    /// tails.
+    pub fn restore(&self, inputs: &MergeInputs, files: &[&Path]) -> anyhow::Result<Option<Manifest>> {
+        let manifest = match Manifest::load(&self.directory)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        anyhow::ensure!(
+            manifest.inputs.lightframes == inputs.lightframes
+                && manifest.inputs.calibration == inputs.calibration
+                && manifest.inputs.fingerprint()? == inputs.fingerprint()?,
+            "The checkpoint in {:#?} belongs to a merge of other files or with other settings",
+            self.directory
+        );
+        anyhow::ensure!(
+            manifest.included.len() <= files.len()
+                && manifest.included.iter().zip(files).all(|(x, y)| x.as_path() == *y),
+            "The checkpoint in {:#?} doesn't include the files in the order of the merge",
+            self.directory
+        );
+
+        info!("Continuing from the checkpoint after {} files", manifest.included.len());
+        Ok(Some(manifest))
+    }
+
+    /// Writes the frame into a new state directory and replaces the manifest afterwards, such that an interruption
+    /// while saving keeps the previous checkpoint.
+    pub fn save(&self, frame: &Frame, inputs: &MergeInputs, included: &[PathBuf]) -> anyhow::Result<()> {
+        let state = format!("{}{}", STATE_PREFIX, included.len());
+        let state_directory = self.directory.join(&state);
+        fs::create_dir_all(&state_directory)
+            .with_context(|| format!("Could not create directory {:#?}", state_directory))?;
+
+        let manifest = Manifest {
+            version: VERSION,
+            inputs: inputs.clone(),
+            included: included.to_vec(),
+            frame: frame.save(&state_directory)?,
+            state: state.clone(),
+        };
+
+        let path = self.directory.join(MANIFEST);
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(&manifest)?)
+            .with_context(|| format!("Could not write checkpoint {:#?}", temporary))?;
+        fs::rename(&temporary, &path).with_context(|| format!("Could not write checkpoint {:#?}", path))?;
+
+        // Remove the states of the previous checkpoints
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(STATE_PREFIX) && name != state && entry.path().is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        info!("Saved checkpoint after {} files to {:#?}", included.len(), self.directory);
+        Ok(())
+    }
+}
+
+/// Parts of a saved frame, each stored as DNG with the metadata and the current image
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FrameParts {
+    #[serde(default)]
+    pub lightframe: Option<ImagePart>,
+    #[serde(default)]
+    pub darkframe: Option<ImagePart>,
+    #[serde(default)]
+    pub bias: Option<ImagePart>,
+    #[serde(default)]
+    pub flat: Option<ImagePart>,
+    #[serde(default)]
+    pub dark_flat: Option<ImagePart>,
+    /// First and last lightframe for gap filling
+    #[serde(default)]
+    pub boundaries: Option<(ImagePart, ImagePart)>,
+    #[serde(default)]
+    pub stack: Option<StackPart>,
+    /// File of the sky mask of composites or of the comet fading
+    #[serde(default)]
+    pub mask: Option<String>,
+}
+
+impl FrameParts {
+    /// Number of lightframes in the frame.
+    pub fn num_images(&self) -> usize {
+        let stack = match &self.stack {
+            Some(StackPart::Sum { image, .. }) | Some(StackPart::Order { image, .. }) => Some(image),
+            None => None,
+        };
+        self.lightframe.as_ref().or(stack).map_or(0, |x| x.num_images)
+    }
+}
+
+/// Image of a part, whose full-precision data is stored next to it in files with the same name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImagePart {
+    pub name: String,
+    pub num_images: usize,
+}
+
+impl ImagePart {
+    pub fn path(&self, directory: &Path, extension: &str) -> PathBuf {
+        directory.join(format!("{}.{}", self.name, extension))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StackPart {
+    Sum {
+        image: ImagePart,
+        /// Whether the sums of squares are stored
+        squares: bool,
+        /// Whether the number of values per pixel is stored, as values were rejected
+        counts: bool,
+    },
+    Order {
+        image: ImagePart,
+        statistic: OrderStatistic,
+        depth: usize,
+        capacity: usize,
+    },
+}
+
+/// Sample type of the full-precision data, which is stored in little endian
+pub trait Sample: Copy {
+    const SIZE: usize;
+
+    fn append_to(self, bytes: &mut Vec<u8>);
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty),*) => {
+        $(impl Sample for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn append_to(self, bytes: &mut Vec<u8>) {
+                bytes.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                let mut buffer = [0u8; std::mem::size_of::<$t>()];
+                buffer.copy_from_slice(bytes);
+                <$t>::from_le_bytes(buffer)
+            }
+        })*
+    };
+}
+
+impl_sample!(u16, u32, u64);
+
+pub fn write_samples<T: Sample>(path: &Path, samples: &[T]) -> anyhow::Result<()> {
+    let file = fs::File::create(path).with_context(|| format!("Could not create {:#?}", path))?;
+    let mut writer = BufWriter::new(file);
+
+    let mut bytes = Vec::with_capacity(T::SIZE * 4096);
+    for chunk in samples.chunks(4096) {
+        bytes.clear();
+        chunk.iter().for_each(|x| x.append_to(&mut bytes));
+        writer.write_all(&bytes)?;
+    }
+
+    writer.flush().with_context(|| format!("Could not write {:#?}", path))
+}
+
+/// Reads the samples of the file, which has to hold exactly the given number.
+pub fn read_samples<T: Sample>(path: &Path, count: usize) -> anyhow::Result<Vec<T>> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {:#?}", path))?;
+    anyhow::ensure!(bytes.len() == count * T::SIZE, "{:#?} doesn't match the size of its image", path);
+
+    Ok(bytes.chunks_exact(T::SIZE).map(T::from_bytes).collect())
+}
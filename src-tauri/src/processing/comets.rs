@@ -0,0 +1,352 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Describes how the intensity of the lightframes develops over the sequence.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "direction", rename_all = "lowercase")]
+pub enum Comets {
+    /// All lightframes are weighted identically
+    Normal,
+    /// Later frames become darker, such that stars fade away
+    Falling {
+        curve: CometCurve,
+        #[serde(default)]
+        floor: f32,
+    },
+    /// Earlier frames are darker, such that stars fade in
+    Raising {
+        curve: CometCurve,
+        #[serde(default)]
+        floor: f32,
+    },
+    /// Arbitrary curve, given as (position, intensity) points with positions from 0 (first) to 1 (last frame)
+    Sampled { points: Vec<(f32, f32)> },
+    /// Stars have a bright head and a tail that fades out over the given number of frames
+    Tail { length: usize },
+}
+
+/// The shape of the intensity curve, starting at 1.0 in the brightest frame.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum CometCurve {
+    Linear,
+    Exponential {
+        rate: f32,
+    },
+    Sigmoid {
+        steepness: f32,
+    },
+    /// Linear fading over a fixed number of frames
+    Length {
+        frames: usize,
+    },
+}
+
+/// Determines the position of a lightframe in the sequence.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CometTiming {
+    /// The position in the list of lightframes
+    #[default]
+    Index,
+    /// The capture time relative to the span of the sequence
+    Timestamp,
+}
+
+impl Comets {
+    /// Returns the intensity of the lightframe at `position` in a sequence of `count` lightframes.
+    ///
+    /// The position is the index of the frame, but may be fractional when derived from capture times.
+    pub fn intensity(&self, position: f32, count: usize) -> f32 {
+        let intensity = match self {
+            Comets::Normal => 1.0,
+            Comets::Falling { curve, floor } => with_floor(curve.value(position, count), *floor),
+            Comets::Raising { curve, floor } => {
+                with_floor(curve.value((count.max(1) - 1) as f32 - position, count), *floor)
+            }
+            Comets::Sampled { points } => interpolate(points, position / (count.max(2) - 1) as f32),
+            // The tail fades with the distance to the last frame
+            Comets::Tail { .. } => {
+                let decay = self.tail_decay().unwrap_or(1.0);
+                decay.powf((count.max(1) - 1) as f32 - position)
+            }
+        };
+
+        intensity.clamp(0.0, 1.0)
+    }
+
+    /// Returns the intensity of every lightframe of an exported sequence and the factor by which the merged frames fade
+    /// before it, such that every output applies the curve over its own frames with the comet head at its newest one.
+    ///
+    /// The merged frames only fade as a whole, which is exact for linear curves and approximates the other shapes.
+    /// Comets with their head at the first frame return `None`, as every output starts at full brightness with the
+    /// intensities of the whole sequence.
+    pub fn sequence_fading(&self, positions: &[f32]) -> Option<Vec<(f32, f32)>> {
+        let count = positions.len();
+        let head_at_end = self.intensity(count.max(1) as f32 - 1.0, count) > self.intensity(0.0, count);
+        if matches!(self, Comets::Normal) || !head_at_end {
+            return None;
+        }
+
+        // Position of frame `k` within the output that ends at frame `n`
+        let relative = |k: usize, n: usize| match positions[n] > 0.0 {
+            true => positions[k] / positions[n] * n as f32,
+            false => k as f32,
+        };
+
+        let fading = (0..count)
+            .map(|n| {
+                let intensity = self.intensity(n as f32, n + 1);
+                let fade = match n {
+                    0 => 1.0,
+                    _ => {
+                        let newest = self.intensity((n - 1) as f32, n);
+                        match newest > 0.0 {
+                            true => self.intensity(relative(n - 1, n), n + 1) / newest,
+                            false => 0.0,
+                        }
+                    }
+                };
+                (intensity, fade)
+            })
+            .collect();
+
+        Some(fading)
+    }
+
+    /// Returns the factor by which the merged frames of a comet tail fade with every following frame.
+    ///
+    /// The fading is exponential, such that a merged part of the sequence fades as a whole when further frames are
+    /// appended to it. The tail falls below 1% of the intensity of the head after its length.
+    pub fn tail_decay(&self) -> Option<f32> {
+        match self {
+            Comets::Tail { length } => Some(0.01f32.powf(1.0 / (*length).max(1) as f32)),
+            _ => None,
+        }
+    }
+}
+
+impl CometCurve {
+    /// Returns the intensity of a frame that is `distance` frames away from the brightest one, which reaches the end
+    /// of the curve at the other end of the sequence.
+    fn value(&self, distance: f32, count: usize) -> f32 {
+        let t = distance / (count.max(2) - 1) as f32;
+
+        match self {
+            CometCurve::Linear => 1.0 - t,
+            CometCurve::Exponential { rate } => (-rate * t).exp(),
+            CometCurve::Sigmoid { steepness } => {
+                let sigmoid = |x: f32| 1.0 / (1.0 + (steepness * (x - 0.5)).exp());
+                (sigmoid(t) - sigmoid(1.0)) / (sigmoid(0.0) - sigmoid(1.0))
+            }
+            CometCurve::Length { frames } => 1.0 - distance / (*frames).max(1) as f32,
+        }
+    }
+}
+
+fn with_floor(value: f32, floor: f32) -> f32 {
+    floor + (1.0 - floor) * value.max(0.0)
+}
+
+fn interpolate(points: &[(f32, f32)], position: f32) -> f32 {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let before = sorted.iter().rev().find(|p| p.0 <= position);
+    let after = sorted.iter().find(|p| p.0 > position);
+
+    match (before, after) {
+        (Some(b), Some(a)) => b.1 + (a.1 - b.1) * (position - b.0) / (a.0 - b.0),
+        (Some(p), None) | (None, Some(p)) => p.1,
+        (None, None) => 1.0,
+    }
+}
+
+/// Parses descriptions like `falling`, `raising:exponential=3:floor=0.1`, `tail:length=30` or
+/// `sampled:0=1,0.5=0.2,1=0`.
+impl FromStr for Comets {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let direction = parts.next().unwrap_or_default();
+
+        if direction == "normal" {
+            anyhow::ensure!(parts.next().is_none(), "The normal mode takes no parameters");
+            return Ok(Comets::Normal);
+        }
+
+        if direction == "sampled" {
+            let points = parts
+                .next()
+                .context("Sampled curves need a list of points, like 'sampled:0=1,1=0'")?
+                .split(',')
+                .map(parse_assignment)
+                .collect::<anyhow::Result<Vec<(f32, f32)>>>()?;
+            anyhow::ensure!(
+                points
+                    .iter()
+                    .all(|(t, v)| (0.0..=1.0).contains(t) && (0.0..=1.0).contains(v)),
+                "Positions and intensities of sampled curves have to be between 0 and 1"
+            );
+            return Ok(Comets::Sampled { points });
+        }
+
+        if direction == "tail" {
+            let length = parts
+                .next()
+                .and_then(|x| x.strip_prefix("length="))
+                .context("Comet tails need a length, like 'tail:length=30'")?
+                .parse()?;
+            anyhow::ensure!(length > 0, "The length of comet tails has to be positive");
+            return Ok(Comets::Tail { length });
+        }
+
+        let mut curve = CometCurve::Linear;
+        let mut floor = 0.0;
+        for part in parts {
+            let (key, value) = part.split_once('=').unwrap_or((part, ""));
+            match key {
+                "linear" => curve = CometCurve::Linear,
+                "exponential" => curve = CometCurve::Exponential { rate: value.parse()? },
+                "sigmoid" => {
+                    let steepness = value.parse()?;
+                    anyhow::ensure!(steepness > 0.0, "The steepness of sigmoid curves has to be positive");
+                    curve = CometCurve::Sigmoid { steepness }
+                }
+                "length" => curve = CometCurve::Length { frames: value.parse()? },
+                "floor" => floor = value.parse()?,
+                _ => anyhow::bail!("Unknown comet parameter '{}'", part),
+            }
+        }
+        anyhow::ensure!((0.0..=1.0).contains(&floor), "The floor has to be between 0 and 1");
+
+        match direction {
+            "falling" => Ok(Comets::Falling { curve, floor }),
+            "raising" => Ok(Comets::Raising { curve, floor }),
+            _ => anyhow::bail!("Unknown comet mode '{}'", direction),
+        }
+    }
+}
+
+fn parse_assignment(s: &str) -> anyhow::Result<(f32, f32)> {
+    let (position, intensity) = s.split_once('=').with_context(|| format!("Invalid point '{}'", s))?;
+    Ok((position.trim().parse()?, intensity.trim().parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNT: usize = 10;
+
+    fn curves() -> Vec<CometCurve> {
+        vec![
+            CometCurve::Linear,
+            CometCurve::Exponential { rate: 3.0 },
+            CometCurve::Sigmoid { steepness: 10.0 },
+            CometCurve::Length { frames: 4 },
+        ]
+    }
+
+    /// Intensity at the other end of the sequence, which is 0 for all curves but the exponential one.
+    fn end_value(curve: &CometCurve) -> f32 {
+        match curve {
+            CometCurve::Exponential { rate } => (-rate).exp(),
+            _ => 0.0,
+        }
+    }
+
+    fn assert_close(x: f32, y: f32) {
+        assert!((x - y).abs() < 1e-5, "{} != {}", x, y);
+    }
+
+    #[test]
+    fn falling_starts_at_full_intensity() {
+        for curve in curves() {
+            let comets = Comets::Falling {
+                curve: curve.clone(),
+                floor: 0.0,
+            };
+
+            assert_close(comets.intensity(0.0, COUNT), 1.0);
+            assert_close(comets.intensity((COUNT - 1) as f32, COUNT), end_value(&curve));
+        }
+    }
+
+    #[test]
+    fn raising_mirrors_falling() {
+        for curve in curves() {
+            let falling = Comets::Falling {
+                curve: curve.clone(),
+                floor: 0.2,
+            };
+            let raising = Comets::Raising {
+                curve: curve.clone(),
+                floor: 0.2,
+            };
+
+            assert_close(raising.intensity((COUNT - 1) as f32, COUNT), 1.0);
+            assert_close(raising.intensity(0.0, COUNT), 0.2 + 0.8 * end_value(&curve));
+            for i in 0..COUNT {
+                let position = i as f32;
+                let mirrored = (COUNT - 1 - i) as f32;
+                assert_close(raising.intensity(position, COUNT), falling.intensity(mirrored, COUNT));
+            }
+        }
+    }
+
+    #[test]
+    fn single_frame_length_keeps_the_brightest_frame() {
+        let raising = Comets::Raising {
+            curve: CometCurve::Length { frames: 1 },
+            floor: 0.0,
+        };
+
+        assert_close(raising.intensity((COUNT - 1) as f32, COUNT), 1.0);
+        assert_close(raising.intensity((COUNT - 2) as f32, COUNT), 0.0);
+    }
+
+    #[test]
+    fn sequence_outputs_end_at_the_comet_head() {
+        let raising = Comets::Raising {
+            curve: CometCurve::Linear,
+            floor: 0.0,
+        };
+        let positions: Vec<f32> = (0..COUNT).map(|i| i as f32).collect();
+        let fading = raising.sequence_fading(&positions).unwrap();
+
+        // The first output holds only its first frame, which is the head of the comet
+        assert_close(fading[0].0, 1.0);
+
+        // Every output fades its frames like a merge of only these frames
+        for n in 0..COUNT {
+            for k in 0..=n {
+                let weight = fading[k].0 * fading[k + 1..=n].iter().map(|(_, fade)| fade).product::<f32>();
+                assert_close(weight, raising.intensity(k as f32, n + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_keeps_the_head_of_falling_comets() {
+        let falling = Comets::Falling {
+            curve: CometCurve::Linear,
+            floor: 0.0,
+        };
+        let positions: Vec<f32> = (0..COUNT).map(|i| i as f32).collect();
+
+        assert!(falling.sequence_fading(&positions).is_none());
+        assert_close(falling.intensity(0.0, COUNT), 1.0);
+    }
+
+    #[test]
+    fn sigmoid_needs_positive_steepness() {
+        assert!("falling:sigmoid=0".parse::<Comets>().is_err());
+        assert!("falling:sigmoid=-2".parse::<Comets>().is_err());
+        assert!("falling:sigmoid=4".parse::<Comets>().is_ok());
+    }
+}
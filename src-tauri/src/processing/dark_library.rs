@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::fileinfo::ImageCandidate;
+use crate::processing::masters::{CalibrationType, MasterInfo};
+
+/// Mismatch of darkframes from another body of the same camera model
+const SERIAL_MISMATCH: f32 = 0.5;
+
+/// Directory of darkframes and master darks, from which the best match for the lightframes is chosen
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DarkLibrary {
+    pub directory: PathBuf,
+    /// Mismatch in stops of dark current above which the match is reported as poor
+    #[serde(default = "default_max_mismatch")]
+    pub max_mismatch: f32,
+}
+
+pub fn default_max_mismatch() -> f32 {
+    0.5
+}
+
+/// Recording conditions, which determine the noise of a darkframe.
+///
+/// The sensor temperature isn't compared, as it is only recorded in the maker notes, which aren't decoded.
+#[derive(Clone, Debug, Serialize)]
+pub struct Conditions {
+    pub camera: String,
+    pub serial: Option<String>,
+    pub iso: Option<u32>,
+    /// Exposure time of a single frame in seconds
+    pub exposure: Option<f32>,
+}
+
+impl Conditions {
+    /// Reads the conditions from the EXIF data, preferring the metadata of masters.
+    pub fn load(path: &Path) -> anyhow::Result<Conditions> {
+        let candidate = ImageCandidate::load(path)?;
+        let master = MasterInfo::load(path)?;
+
+        Ok(Conditions {
+            camera: candidate.camera.clone(),
+            serial: candidate.serial_number(),
+            iso: master.as_ref().and_then(|x| x.iso).or_else(|| candidate.iso()),
+            exposure: master
+                .as_ref()
+                .and_then(|x| x.exposure)
+                .or_else(|| candidate.exposure_seconds()),
+        })
+    }
+
+    /// Groups frames with the same conditions, such that they are averaged together.
+    fn key(&self) -> String {
+        format!(
+            "{}|{}|{:?}|{:?}",
+            self.camera.to_lowercase(),
+            self.serial.clone().unwrap_or_default(),
+            self.iso,
+            self.exposure.map(|x| (x * 1000.0).round() as i64)
+        )
+    }
+
+    /// Estimates how far the dark current of a darkframe with these conditions is off, in stops.
+    ///
+    /// Returns `None` for other camera models and the differences that cause the mismatch otherwise.
+    fn mismatch(&self, light: &Conditions) -> Option<(f32, Vec<String>)> {
+        if !self.camera.eq_ignore_ascii_case(&light.camera) {
+            return None;
+        }
+
+        let mut mismatch = 0.0;
+        let mut differences = Vec::new();
+        if let (Some(dark), Some(light)) = (&self.serial, &light.serial) {
+            if dark != light {
+                mismatch += SERIAL_MISMATCH;
+                differences.push(format!("taken with camera {} instead of {}", dark, light));
+            }
+        }
+        if let (Some(dark), Some(light)) = (self.iso.filter(|x| *x > 0), light.iso.filter(|x| *x > 0)) {
+            let stops = (dark as f32 / light as f32).log2().abs();
+            if stops > 0.0 {
+                mismatch += stops;
+                differences.push(format!("ISO {} instead of {}", dark, light));
+            }
+        }
+        if let (Some(dark), Some(light)) = (self.exposure.filter(|x| *x > 0.0), light.exposure.filter(|x| *x > 0.0)) {
+            let stops = (dark / light).log2().abs();
+            if stops > 0.01 {
+                mismatch += stops;
+                differences.push(format!("exposure of {}s instead of {}s", dark, light));
+            }
+        }
+
+        Some((mismatch, differences))
+    }
+}
+
+/// Darkframes of the library that match the lightframes best
+#[derive(Clone, Debug, Serialize)]
+pub struct DarkMatch {
+    pub files: Vec<PathBuf>,
+    pub conditions: Conditions,
+    /// Estimated difference of the dark current in stops
+    pub mismatch: f32,
+    /// Differences of the conditions, if the match is poor
+    pub warnings: Vec<String>,
+}
+
+impl DarkLibrary {
+    /// Reads the conditions of all darkframes in the directory and its subdirectories.
+    ///
+    /// Files that can't be decoded and masters of other calibration types are skipped.
+    pub fn index(&self) -> anyhow::Result<Vec<(PathBuf, Conditions)>> {
+        let files = list_files(&self.directory)?;
+
+        Ok(files
+            .par_iter()
+            .filter(|path| path.extension().map(|x| x != "json").unwrap_or(true))
+            .filter_map(|path| {
+                match MasterInfo::load(path) {
+                    Ok(Some(info)) if info.calibration_type != CalibrationType::Dark => return None,
+                    Err(err) => warn!("Skipping {:#?} in the dark library: {:#}", path, err),
+                    _ => {}
+                }
+
+                match Conditions::load(path) {
+                    Ok(conditions) => Some((path.clone(), conditions)),
+                    Err(err) => {
+                        debug!("Skipping {:#?} in the dark library: {:#}", path, err);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Chooses the darkframes whose conditions are closest to the given lightframe.
+    pub fn find_match(&self, lightframe: &Path) -> anyhow::Result<Option<DarkMatch>> {
+        let light = Conditions::load(lightframe)
+            .with_context(|| format!("Could not read the conditions of {:#?}", lightframe))?;
+
+        // Frames with the same conditions are averaged together
+        let mut groups: BTreeMap<String, (Conditions, Vec<PathBuf>)> = BTreeMap::new();
+        for (path, conditions) in self.index()? {
+            groups
+                .entry(conditions.key())
+                .or_insert_with(|| (conditions, Vec::new()))
+                .1
+                .push(path);
+        }
+
+        let best = groups
+            .into_values()
+            .filter_map(|(conditions, files)| {
+                let (mismatch, differences) = conditions.mismatch(&light)?;
+                Some((mismatch, differences, conditions, files))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+
+        let dark_match = best.map(|(mismatch, differences, conditions, files)| {
+            let warnings = if mismatch > self.max_mismatch {
+                differences
+            } else {
+                Vec::new()
+            };
+            DarkMatch {
+                files,
+                conditions,
+                mismatch,
+                warnings,
+            }
+        });
+
+        match &dark_match {
+            Some(x) if !x.warnings.is_empty() => {
+                warn!("Best darkframes of the library are off: {}", x.warnings.join(", "))
+            }
+            Some(x) => info!("Using {} darkframes of the library", x.files.len()),
+            None => warn!("The dark library has no darkframes of the camera {}", light.camera),
+        }
+
+        Ok(dark_match)
+    }
+}
+
+fn list_files(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let entries = fs::read_dir(directory).with_context(|| format!("Could not read directory {:#?}", directory))?;
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.append(&mut list_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
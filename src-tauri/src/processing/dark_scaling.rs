@@ -0,0 +1,82 @@
+use clap::ValueEnum;
+use log::warn;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::gap_filling::SampleLayout;
+
+/// Largest factor the optimisation may scale the darkframe by without an exposure ratio
+const MAX_OPTIMIZED_SCALE: f32 = 4.0;
+
+/// Factor by which the optimisation may deviate from the exposure ratio
+const MAX_REFINEMENT: f32 = 2.0;
+
+/// Scales the darkframe, if its exposure time or temperature doesn't match the lightframes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DarkScaling {
+    /// Scales by the ratio of the exposure times of a single lightframe and darkframe
+    Exposure,
+    /// Chooses the factor that minimises the residual noise of the calibrated image
+    Optimize,
+    /// Refines the exposure ratio by minimising the residual noise
+    Combined,
+}
+
+impl DarkScaling {
+    /// Determines the factor for the thermal noise of the darkframe.
+    ///
+    /// The exposure times are the ones of a single frame, and a missing exposure ratio counts as 1.
+    pub fn scale(
+        &self,
+        light: &[u16],
+        thermal: &[f32],
+        layout: &SampleLayout,
+        exposure_light: Option<f32>,
+        exposure_dark: Option<f32>,
+    ) -> f32 {
+        let ratio = match (exposure_light, exposure_dark) {
+            (Some(light), Some(dark)) if light > 0.0 && dark > 0.0 => Some(light / dark),
+            _ => None,
+        };
+        if ratio.is_none() && *self != DarkScaling::Optimize {
+            warn!("The exposure times are unknown, the darkframe is not scaled by them");
+        }
+
+        let bounds = match (self, ratio) {
+            (DarkScaling::Exposure, _) => return ratio.unwrap_or(1.0),
+            (DarkScaling::Combined, Some(ratio)) => (ratio / MAX_REFINEMENT, ratio * MAX_REFINEMENT),
+            _ => (0.0, MAX_OPTIMIZED_SCALE),
+        };
+
+        minimise_noise(light, thermal, layout)
+            .map(|x| x.clamp(bounds.0, bounds.1))
+            .or(ratio)
+            .unwrap_or(1.0)
+    }
+}
+
+/// Finds the factor for the thermal noise, which minimises the noise that remains after subtracting it.
+///
+/// Both are compared on the differences of horizontally neighbouring samples of the same color, such that smooth
+/// signals like the sky background and vignetting don't contribute. The factor is the least squares fit, which is
+/// dominated by the hot pixels. Returns `None` if the darkframe holds no noise.
+pub fn minimise_noise(light: &[u16], thermal: &[f32], layout: &SampleLayout) -> Option<f32> {
+    let offset = layout.step_x.max(1) * layout.cpp;
+
+    let (correlation, energy) = (0..light.len().saturating_sub(offset))
+        .into_par_iter()
+        .filter(|i| i % layout.row_length + offset < layout.row_length)
+        .map(|i| {
+            let l = light[i + offset] as f64 - light[i] as f64;
+            let t = thermal[i + offset] as f64 - thermal[i] as f64;
+            (l * t, t * t)
+        })
+        .reduce(|| (0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+    if energy < f64::EPSILON {
+        return None;
+    }
+
+    Some((correlation / energy) as f32)
+}
@@ -1,4 +1,5 @@
 use crate::anyhow::Context;
+use crate::processing::transients::BoundingBox;
 use crate::program_description;
 use std::io::Cursor;
 use std::{fs::File, path::PathBuf};
@@ -27,7 +28,8 @@ const DNG_VERSION_V1_6: [u8; 4] = [1, 6, 0, 0];
 
 pub struct ImageWriter {
     raw_image: RawImage,
-    preview: DynamicImage,
+    /// Developed image for the thumbnail and the previews, which is left out for intermediate results
+    preview: Option<DynamicImage>,
     exif: Exif,
 }
 
@@ -49,11 +51,24 @@ impl ImageWriter {
 
         Ok(Self {
             raw_image,
-            preview,
+            preview: Some(preview),
             exif,
         })
     }
 
+    /// Creates a writer for the raw data only, skipping the development of the preview.
+    pub fn raw(raw_image: RawImage, exif: Exif) -> Self {
+        Self {
+            raw_image,
+            preview: None,
+            exif,
+        }
+    }
+
+    fn preview(&self) -> anyhow::Result<&DynamicImage> {
+        self.preview.as_ref().context("The image has no preview")
+    }
+
     pub fn write_dng(&self, path: PathBuf) -> anyhow::Result<()> {
         info!("Writing DNG to {:?}...", path);
 
@@ -72,8 +87,10 @@ impl ImageWriter {
         root_ifd.add_tag(DngTag::RawDataUniqueID, uiid)?;
 
         // Add a thumbnail
-        root_ifd.add_tag(TiffCommonTag::NewSubFileType, 1_u16)?;
-        dng_put_thumbnail(&mut root_ifd, &self.preview).unwrap();
+        if let Some(preview) = &self.preview {
+            root_ifd.add_tag(TiffCommonTag::NewSubFileType, 1_u16)?;
+            dng_put_thumbnail(&mut root_ifd, preview).unwrap();
+        }
 
         // Add basic info
         root_ifd.add_tag(TiffCommonTag::Software, &program_description())?;
@@ -131,12 +148,11 @@ impl ImageWriter {
         sub_ifds.push(raw_offset);
 
         // Add preview image
-        let preview_offset = {
+        if let Some(preview) = &self.preview {
             let mut prev_image_ifd = root_ifd.new_directory();
-            dng_put_preview(&mut prev_image_ifd, &self.preview)?;
-            prev_image_ifd.build()?
-        };
-        sub_ifds.push(preview_offset);
+            dng_put_preview(&mut prev_image_ifd, preview)?;
+            sub_ifds.push(prev_image_ifd.build()?);
+        }
 
         // Finalize DNG file by updating IFD0 offset
         root_ifd.add_tag(TiffCommonTag::SubIFDs, &sub_ifds)?;
@@ -221,16 +237,41 @@ impl ImageWriter {
 
     pub fn write_preview_jpg(&self, path: PathBuf) -> anyhow::Result<()> {
         info!("Writing preview to {:?}...", path);
-        let img = self.preview.clone().into_rgb8();
+        let img = self.preview()?.clone().into_rgb8();
         img.save(path)?;
 
         Ok(())
     }
 
+    /// Writes the part of the preview that shows the given region of the raw data, with a margin around it.
+    pub fn write_preview_crop(&self, path: PathBuf, region: &BoundingBox, margin: usize) -> anyhow::Result<()> {
+        info!("Writing cropped preview to {:?}...", path);
+
+        // The developed preview may be scaled relative to the raw data
+        let preview = self.preview()?;
+        let scale_x = preview.width() as f32 / self.raw_image.width as f32;
+        let scale_y = preview.height() as f32 / self.raw_image.height as f32;
+
+        let x = region.x.saturating_sub(margin);
+        let y = region.y.saturating_sub(margin);
+        let width = region.width + region.x - x + margin;
+        let height = region.height + region.y - y + margin;
+
+        let img = preview.crop_imm(
+            (x as f32 * scale_x) as u32,
+            (y as f32 * scale_y) as u32,
+            (width as f32 * scale_x).ceil() as u32,
+            (height as f32 * scale_y).ceil() as u32,
+        );
+        img.into_rgb8().save(path)?;
+
+        Ok(())
+    }
+
     pub fn get_preview_bytes(&self) -> anyhow::Result<Vec<u8>> {
         info!("Creating preview file in memory...");
 
-        let img = self.preview.clone().into_rgb8();
+        let img = self.preview()?.clone().into_rgb8();
         let mut cursor = Cursor::new(Vec::new());
         img.write_to(&mut cursor, ImageFormat::Jpeg)?;
 
@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::gap_filling::SampleLayout;
+
+/// Number of noise standard deviations by which a star exceeds the sky background
+const STAR_THRESHOLD: f32 = 5.0;
+
+/// Settings to find lightframes that are disturbed by clouds, headlights or flashes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FrameRejection {
+    #[serde(default)]
+    pub action: RejectionAction,
+    /// Maximum relative change of the mean brightness compared to the baseline
+    #[serde(default = "default_max_brightness_change")]
+    pub max_brightness_change: f32,
+    /// Maximum relative change of the sky background compared to the baseline
+    #[serde(default = "default_max_background_change")]
+    pub max_background_change: f32,
+    /// Minimum fraction of the stars of the baseline that have to be visible
+    #[serde(default = "default_min_star_fraction")]
+    pub min_star_fraction: f32,
+    /// Number of preceding lightframes that form the baseline
+    #[serde(default = "default_baseline_frames")]
+    pub baseline_frames: usize,
+}
+
+pub fn default_max_brightness_change() -> f32 {
+    0.5
+}
+
+pub fn default_max_background_change() -> f32 {
+    0.5
+}
+
+pub fn default_min_star_fraction() -> f32 {
+    0.5
+}
+
+pub fn default_baseline_frames() -> usize {
+    10
+}
+
+/// What happens to lightframes that deviate from the baseline
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RejectionAction {
+    /// Leaves the frames out of the merge
+    #[default]
+    Reject,
+    /// Merges the frames, but lists them in the report
+    Flag,
+}
+
+/// Brightness statistics of a lightframe above the black level
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct FrameStatistics {
+    /// Mean of all samples
+    pub mean: f32,
+    /// Median brightness, which is dominated by the sky background
+    pub background: f32,
+    /// Number of local maxima that stand out of the noise of the background
+    pub stars: usize,
+}
+
+/// Lightframe that deviates from the baseline of the preceding frames
+#[derive(Clone, Debug, Serialize)]
+pub struct FlaggedFrame {
+    pub path: PathBuf,
+    pub statistics: FrameStatistics,
+    pub reasons: Vec<String>,
+    /// Whether the frame was left out of the merge
+    pub rejected: bool,
+}
+
+/// Running baseline over the statistics of the preceding lightframes.
+///
+/// The baseline is the median of the last frames, including deviating ones, such that isolated outliers are ignored
+/// while lasting changes like a rising moon become the new normal.
+pub struct Baseline {
+    settings: FrameRejection,
+    frames: VecDeque<FrameStatistics>,
+}
+
+impl Baseline {
+    pub fn new(settings: FrameRejection) -> Baseline {
+        Baseline {
+            frames: VecDeque::with_capacity(settings.baseline_frames),
+            settings,
+        }
+    }
+
+    /// Compares the statistics of a frame with the baseline and adds them to it afterwards.
+    ///
+    /// Returns the reasons why the frame deviates, which is empty for good frames.
+    pub fn check(&mut self, statistics: FrameStatistics) -> Vec<String> {
+        let mut reasons = Vec::new();
+
+        if !self.frames.is_empty() {
+            let mean = median(self.frames.iter().map(|x| x.mean).collect());
+            let background = median(self.frames.iter().map(|x| x.background).collect());
+            let stars = median(self.frames.iter().map(|x| x.stars as f32).collect());
+
+            let brightness_change = relative_change(statistics.mean, mean);
+            if brightness_change > self.settings.max_brightness_change {
+                reasons.push(format!("brightness changed by {:.0}%", 100.0 * brightness_change));
+            }
+            let background_change = relative_change(statistics.background, background);
+            if background_change > self.settings.max_background_change {
+                reasons.push(format!("sky background changed by {:.0}%", 100.0 * background_change));
+            }
+            if (statistics.stars as f32) < self.settings.min_star_fraction * stars {
+                reasons.push(format!("only {} of about {:.0} stars visible", statistics.stars, stars));
+            }
+        }
+
+        if self.frames.len() >= self.settings.baseline_frames.max(1) {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(statistics);
+
+        reasons
+    }
+
+    pub fn rejects(&self) -> bool {
+        self.settings.action == RejectionAction::Reject
+    }
+}
+
+/// Measures the statistics on blocks that each cover one repetition of the CFA pattern, such that all colors are
+/// combined.
+pub fn measure(data: &[u16], layout: &SampleLayout, black_level: f32) -> FrameStatistics {
+    let step_x = layout.step_x.max(1);
+    let step_y = layout.step_y.max(1);
+    let block_length = step_x * layout.cpp;
+    let width = layout.row_length / block_length;
+    let height = data.len() / layout.row_length / step_y;
+
+    let blocks: Vec<f32> = (0..height)
+        .into_par_iter()
+        .flat_map_iter(|by| {
+            (0..width).map(move |bx| {
+                let sum: u32 = (by * step_y..(by + 1) * step_y)
+                    .map(|y| {
+                        let start = y * layout.row_length + bx * block_length;
+                        data[start..start + block_length].iter().map(|x| *x as u32).sum::<u32>()
+                    })
+                    .sum();
+                sum as f32 / (block_length * step_y) as f32 - black_level
+            })
+        })
+        .collect();
+
+    let mean = data.par_iter().map(|x| *x as u64).sum::<u64>() as f32 / data.len().max(1) as f32 - black_level;
+    let background = median(blocks.clone());
+
+    // Robust estimation of the noise by the median absolute deviation
+    let sigma = 1.4826 * median(blocks.par_iter().map(|x| (x - background).abs()).collect());
+    let threshold = background + STAR_THRESHOLD * sigma.max(1.0);
+
+    // Stars are local maxima, where ties are counted only once
+    let stars = (0..blocks.len())
+        .into_par_iter()
+        .filter(|i| {
+            let value = blocks[*i];
+            value > threshold
+                && neighbours(*i, width, height).all(|n| if n < *i { value > blocks[n] } else { value >= blocks[n] })
+        })
+        .count();
+
+    FrameStatistics {
+        mean,
+        background,
+        stars,
+    }
+}
+
+fn relative_change(value: f32, baseline: f32) -> f32 {
+    (value - baseline).abs() / baseline.abs().max(1.0)
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let center = values.len() / 2;
+    *values.select_nth_unstable_by(center, |a, b| a.total_cmp(b)).1
+}
+
+/// Returns the indices of the eight surrounding blocks.
+fn neighbours(i: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % width) as isize, (i / width) as isize);
+
+    (-1..=1)
+        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(move |(nx, ny)| {
+            (*nx, *ny) != (x, y) && (0..width as isize).contains(nx) && (0..height as isize).contains(ny)
+        })
+        .map(move |(nx, ny)| ny as usize * width + nx as usize)
+}
@@ -0,0 +1,71 @@
+use std::cmp::{max, min};
+
+use rayon::prelude::*;
+
+/// Describes the layout of the raw data, such that only samples of the same color are combined.
+pub struct SampleLayout {
+    /// Number of samples per row
+    pub row_length: usize,
+    /// Number of pixels between two horizontally neighbouring pixels of the same color
+    pub step_x: usize,
+    /// Number of rows between two vertically neighbouring pixels of the same color
+    pub step_y: usize,
+    /// Number of samples per pixel
+    pub cpp: usize,
+}
+
+/// Computes the connections between the star trails of two consecutive frames.
+///
+/// A pixel is part of a gap if it lies in the center between a bright pixel of the previous frame and a bright pixel
+/// of the next frame. Static features like the foreground or hot pixels are at the same position in both frames and
+/// thus don't get smeared. The offsets are stepped in the size of the CFA pattern, so only same-colored pixels are
+/// combined.
+pub fn bridge(previous: &[u16], next: &[u16], layout: &SampleLayout, max_gap: usize) -> Vec<u16> {
+    let offsets = gap_offsets(layout, max_gap);
+    let rows = previous.len() / layout.row_length;
+    let row_length = layout.row_length as isize;
+
+    let mut res = vec![0u16; previous.len()];
+    res.par_chunks_mut(layout.row_length).enumerate().for_each(|(y, row)| {
+        let y = y as isize;
+
+        for (x, value) in row.iter_mut().enumerate() {
+            let x = x as isize;
+
+            *value = offsets
+                .iter()
+                .filter(|(dx, dy)| {
+                    (0..row_length).contains(&(x - dx))
+                        && (0..row_length).contains(&(x + dx))
+                        && (0..rows as isize).contains(&(y - dy))
+                        && (0..rows as isize).contains(&(y + dy))
+                })
+                .map(|(dx, dy)| {
+                    let before = previous[((y - dy) * row_length + x - dx) as usize];
+                    let after = next[((y + dy) * row_length + x + dx) as usize];
+                    min(before, after)
+                })
+                .fold(0, max);
+        }
+    });
+
+    res
+}
+
+/// Returns all sample offsets from a gap pixel to the end of the previous trail within half of the maximum gap.
+fn gap_offsets(layout: &SampleLayout, max_gap: usize) -> Vec<(isize, isize)> {
+    let radius = (max_gap / 2) as isize;
+    let step_x = max(layout.step_x, 1) as isize;
+    let step_y = max(layout.step_y, 1) as isize;
+
+    let mut offsets = Vec::new();
+    for dy in (-radius / step_y..=radius / step_y).map(|k| k * step_y) {
+        for dx in (-radius / step_x..=radius / step_x).map(|k| k * step_x) {
+            if (dx != 0 || dy != 0) && dx * dx + dy * dy <= radius * radius {
+                offsets.push((dx * layout.cpp as isize, dy));
+            }
+        }
+    }
+
+    offsets
+}
@@ -1,4 +1,6 @@
 use anyhow;
+use anyhow::Context;
+use chrono::NaiveDateTime;
 use log::info;
 use num::rational::Ratio;
 use num::ToPrimitive;
@@ -8,10 +10,23 @@ use rawler::{exif::Exif, RawFile, RawImage, RawImageData};
 use std::cmp::max;
 use std::fs::File;
 use std::io::BufReader;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use crate::fileinfo;
+use crate::processing::accumulator::Accumulator;
+use crate::processing::black_level::BlackLevels;
+use crate::processing::checkpoint::{FrameParts, ImagePart};
+use crate::processing::dark_scaling::DarkScaling;
 use crate::processing::dng_writing::ImageWriter;
+use crate::processing::frame_quality::{self, FrameStatistics};
+use crate::processing::gap_filling::{self, SampleLayout};
+use crate::processing::masters::CalibrationType;
+use crate::processing::sky_mask::SkyMask;
+use crate::processing::stacking::{ClippingBounds, Stack};
+use crate::processing::transients::{self, StreakDetection, Streaks};
+use crate::processing::MergeSettings;
 
 use super::status;
 
@@ -28,21 +43,81 @@ pub trait Mergable<Rhs = Self> {
 
 pub struct Frame {
     lightframe: Option<Image>,
-    darkframe: Option<Image>,
+    darkframe: Option<Accumulator>,
+    /// Readout offset of the sensor, averaged from bias frames
+    bias: Option<Accumulator>,
+    /// Vignetting and dust of the optics, averaged from flat frames
+    flat: Option<Accumulator>,
+    /// Offset of the flat frames, averaged from darkframes with the exposure of the flats
+    dark_flat: Option<Accumulator>,
+    /// First and last lightframe of the merged sequence, kept to fill the gaps to the neighbouring frames
+    boundaries: Option<Boundaries>,
+    /// Stacked lightframes for the averaging modes
+    stack: Option<Stack>,
+    /// Mask that combines the maximized lightframe as sky with the stack as foreground, or restricts the comet fading
+    mask: Option<Arc<SkyMask>>,
+}
+
+/// First and last lightframe of a merged range
+enum Boundaries {
+    /// A single lightframe is both boundaries, which is copied only once it is merged into a range
+    Lightframe,
+    Range(Arc<Image>, Arc<Image>),
+}
+
+impl Boundaries {
+    /// Returns the first and last lightframe of the range, copying a single lightframe.
+    fn resolve(self, lightframe: &Image) -> (Arc<Image>, Arc<Image>) {
+        match self {
+            Boundaries::Lightframe => {
+                let image = Arc::new(lightframe.clone());
+                (image.clone(), image)
+            }
+            Boundaries::Range(first, last) => (first, last),
+        }
+    }
 }
 
 impl Frame {
     pub fn from_lightframe(image: Image) -> Frame {
         Frame {
             lightframe: Some(image),
-            darkframe: None,
+            ..Frame::identity()
         }
     }
 
-    pub fn from_darkframe(image: Image) -> Frame {
+    pub fn from_darkframe(image: Accumulator) -> Frame {
         Frame {
-            lightframe: None,
             darkframe: Some(image),
+            ..Frame::identity()
+        }
+    }
+
+    pub fn from_bias(image: Accumulator) -> Frame {
+        Frame {
+            bias: Some(image),
+            ..Frame::identity()
+        }
+    }
+
+    pub fn from_flat(image: Accumulator) -> Frame {
+        Frame {
+            flat: Some(image),
+            ..Frame::identity()
+        }
+    }
+
+    pub fn from_dark_flat(image: Accumulator) -> Frame {
+        Frame {
+            dark_flat: Some(image),
+            ..Frame::identity()
+        }
+    }
+
+    pub fn from_stack(stack: Stack) -> Frame {
+        Frame {
+            stack: Some(stack),
+            ..Frame::identity()
         }
     }
 
@@ -50,36 +125,333 @@ impl Frame {
         Frame {
             lightframe: None,
             darkframe: None,
+            bias: None,
+            flat: None,
+            dark_flat: None,
+            boundaries: None,
+            stack: None,
+            mask: None,
+        }
+    }
+
+    /// Keeps the lightframe as boundary, such that the gap to its neighbours can be filled while merging.
+    pub fn keep_boundaries(self) -> Frame {
+        Frame {
+            boundaries: self.lightframe.as_ref().map(|_| Boundaries::Lightframe),
+            ..self
         }
     }
 
-    pub fn get_image(self) -> anyhow::Result<Image> {
-        match (self.lightframe, self.darkframe) {
-            (Some(light), Some(dark)) => light.apply_darkframe(dark),
-            (Some(light), None) => Ok(light),
+    /// Sets the sky mask, e.g. to restrict the fading of the comet tail to the sky.
+    pub fn with_mask(self, mask: Option<Arc<SkyMask>>) -> Frame {
+        Frame {
+            mask: mask.or(self.mask),
+            ..self
+        }
+    }
+
+    /// Creates a composite of a lightframe, which is maximized in the sky, and a stack for the foreground.
+    ///
+    /// Without a mask, both are merged side by side, e.g. to detect the sky.
+    pub fn composite(image: Image, foreground: Stack, mask: Option<Arc<SkyMask>>) -> Frame {
+        Frame {
+            lightframe: Some(image),
+            stack: Some(foreground),
+            mask,
+            ..Frame::identity()
+        }
+    }
+
+    /// Joins the lightframes of horizontal strips from top to bottom, which were merged from the same files.
+    pub fn join_strips(strips: Vec<Frame>) -> anyhow::Result<Frame> {
+        let (lightframes, stacks): (Vec<_>, Vec<_>) = strips.into_iter().map(|x| (x.lightframe, x.stack)).unzip();
+
+        Ok(Frame {
+            lightframe: join_parts(lightframes, Image::join_strips)?,
+            stack: join_parts(stacks, Stack::join_strips)?,
+            ..Frame::identity()
+        })
+    }
+
+    /// Calibrates the merged lightframes, returning the factor of the darkframe if it was scaled.
+    pub fn get_image(self, dark_scaling: Option<DarkScaling>) -> anyhow::Result<(Image, Option<f32>)> {
+        let lightframe = match (self.lightframe, self.stack, self.mask) {
+            (Some(sky), Some(stack), Some(mask)) => Some(sky.composite(stack.into_image(), &mask)?),
+            (Some(light), _, _) => Some(light),
+            (None, Some(stack), _) => Some(stack.into_image()),
+            (None, None, _) => None,
+        };
+
+        let bias = self.bias.map(Accumulator::into_image);
+        let (calibrated, dark_scale) = match (lightframe, self.darkframe.map(Accumulator::into_image), &bias) {
+            (Some(light), Some(dark), bias) => {
+                let (image, scale) = light.apply_darkframe(dark, bias.as_ref(), dark_scaling)?;
+                (image, dark_scaling.map(|_| scale))
+            }
+            (Some(light), None, Some(bias)) => (light.apply_bias(bias)?, None),
+            (Some(light), None, None) => (light, None),
             _ => anyhow::bail!("The image contains no lightframe"),
+        };
+
+        // Without dark flats, the bias is the best estimate of the offset of the flats
+        let image = match self.flat.map(Accumulator::into_image) {
+            Some(flat) => {
+                let dark_flat = self.dark_flat.map(Accumulator::into_image);
+                calibrated.apply_flat(&flat, dark_flat.as_ref().or(bias.as_ref()))?
+            }
+            None => calibrated,
+        };
+
+        Ok((image, dark_scale))
+    }
+
+    /// Returns the current merge result with the darkframe applied, without consuming the frame.
+    pub fn snapshot(&self, dark_scaling: Option<DarkScaling>) -> anyhow::Result<Image> {
+        Frame {
+            lightframe: self.lightframe.clone(),
+            darkframe: self.darkframe.clone(),
+            bias: self.bias.clone(),
+            flat: self.flat.clone(),
+            dark_flat: self.dark_flat.clone(),
+            boundaries: None,
+            stack: self.stack.clone(),
+            mask: self.mask.clone(),
         }
+        .get_image(dark_scaling)
+        .map(|(image, _)| image)
     }
 
-    pub fn merge(self, other: Frame, state: Arc<Mutex<status::ProcessingStatus>>) -> anyhow::Result<Box<Frame>> {
-        let frame = Frame {
-            lightframe: match (self.lightframe, other.lightframe) {
-                (Some(x), Some(y)) => Some(Frame::count_and_merge(x, y, MergeMode::Maximize, state.clone())?),
-                (Some(x), None) => Some(x),
-                (None, Some(y)) => Some(y),
+    /// Returns copies of the averaged calibration frames.
+    pub fn masters(&self) -> Vec<(CalibrationType, Image)> {
+        [
+            (CalibrationType::Dark, &self.darkframe),
+            (CalibrationType::Bias, &self.bias),
+            (CalibrationType::Flat, &self.flat),
+            (CalibrationType::DarkFlat, &self.dark_flat),
+        ]
+        .iter()
+        .filter_map(|(calibration_type, master)| master.as_ref().map(|x| (*calibration_type, x.to_image())))
+        .collect()
+    }
+
+    /// Estimates the bytes of image data the frame holds, counting shared data like the sky mask only once.
+    pub fn memory_size(&self) -> usize {
+        let calibration: usize = [&self.darkframe, &self.bias, &self.flat, &self.dark_flat]
+            .iter()
+            .filter_map(|x| x.as_ref().map(Accumulator::memory_size))
+            .sum();
+        let boundaries = match &self.boundaries {
+            Some(Boundaries::Range(first, last)) if Arc::ptr_eq(first, last) => first.memory_size(),
+            Some(Boundaries::Range(first, last)) => first.memory_size() + last.memory_size(),
+            Some(Boundaries::Lightframe) | None => 0,
+        };
+
+        self.lightframe.as_ref().map_or(0, Image::memory_size)
+            + calibration
+            + boundaries
+            + self.stack.as_ref().map_or(0, Stack::memory_size)
+    }
+
+    /// Writes every part of the frame into the directory and describes them.
+    pub fn save(&self, directory: &Path) -> anyhow::Result<FrameParts> {
+        let save_accumulator = |x: &Option<Accumulator>, name| x.as_ref().map(|x| x.save(directory, name)).transpose();
+
+        let lightframe = self
+            .lightframe
+            .as_ref()
+            .map(|x| x.clone().save(directory, "lightframe"))
+            .transpose()?;
+
+        Ok(FrameParts {
+            darkframe: save_accumulator(&self.darkframe, "darkframe")?,
+            bias: save_accumulator(&self.bias, "bias")?,
+            flat: save_accumulator(&self.flat, "flat")?,
+            dark_flat: save_accumulator(&self.dark_flat, "dark_flat")?,
+            boundaries: match &self.boundaries {
+                // A single lightframe is both boundaries
+                Some(Boundaries::Lightframe) => lightframe.clone().map(|x| (x.clone(), x)),
+                Some(Boundaries::Range(first, last)) => Some((
+                    first.as_ref().clone().save(directory, "first")?,
+                    last.as_ref().clone().save(directory, "last")?,
+                )),
+                None => None,
+            },
+            stack: self.stack.as_ref().map(|x| x.save(directory, "stack")).transpose()?,
+            lightframe,
+            mask: match &self.mask {
+                Some(mask) => {
+                    mask.save(&directory.join("mask.png"))?;
+                    Some(String::from("mask.png"))
+                }
+                None => None,
+            },
+        })
+    }
+
+    /// Loads a saved frame, which gets the clipping bounds and the mask of the current merge, falling back to the
+    /// saved mask.
+    pub fn load(
+        directory: &Path,
+        parts: &FrameParts,
+        bounds: Option<&Arc<ClippingBounds>>,
+        mask: Option<Arc<SkyMask>>,
+    ) -> anyhow::Result<Frame> {
+        let load_accumulator = |x: &Option<ImagePart>| x.as_ref().map(|x| Accumulator::load(directory, x)).transpose();
+
+        Ok(Frame {
+            lightframe: parts
+                .lightframe
+                .as_ref()
+                .map(|x| Image::load(directory, x))
+                .transpose()?,
+            darkframe: load_accumulator(&parts.darkframe)?,
+            bias: load_accumulator(&parts.bias)?,
+            flat: load_accumulator(&parts.flat)?,
+            dark_flat: load_accumulator(&parts.dark_flat)?,
+            boundaries: match (&parts.boundaries, &parts.lightframe) {
+                (Some((first, _)), Some(lightframe)) if first.name == lightframe.name => Some(Boundaries::Lightframe),
+                (Some((first, last)), _) => {
+                    let image = Arc::new(Image::load(directory, first)?);
+                    match first.name == last.name {
+                        true => Some(Boundaries::Range(image.clone(), image)),
+                        false => Some(Boundaries::Range(image, Arc::new(Image::load(directory, last)?))),
+                    }
+                }
+                (None, _) => None,
+            },
+            stack: parts
+                .stack
+                .as_ref()
+                .map(|x| Stack::load(directory, x, bounds))
+                .transpose()?,
+            mask: match (mask, &parts.mask) {
+                (Some(mask), _) => Some(mask),
+                (None, Some(file)) => Some(Arc::new(SkyMask::load(&directory.join(file))?)),
                 (None, None) => None,
             },
-            darkframe: match (self.darkframe, other.darkframe) {
-                (Some(x), Some(y)) => Some(Frame::count_and_merge(x, y, MergeMode::WeightedAverage, state)?),
+        })
+    }
+
+    pub fn take_stack(self) -> Option<Stack> {
+        self.stack
+    }
+
+    /// Detects the sky from the maximized lightframes and the statistics of the stack.
+    pub fn detect_sky(&self) -> anyhow::Result<SkyMask> {
+        match (&self.lightframe, &self.stack) {
+            (Some(maximum), Some(Stack::Sum(statistics))) => SkyMask::detect(maximum, statistics),
+            _ => anyhow::bail!("The frame holds no statistics to detect the sky"),
+        }
+    }
+
+    /// Merges two frames, where `self` has to precede `other` in the sequence for gap filling and comet tails.
+    pub fn merge(
+        self,
+        other: Frame,
+        settings: &MergeSettings,
+        state: Arc<Mutex<status::ProcessingStatus>>,
+    ) -> anyhow::Result<Box<Frame>> {
+        // The lightframes and the stack of composites hold the same frames, which counts as a single merge
+        let count_stack = self.lightframe.is_none() || other.lightframe.is_none();
+
+        let (lightframe, boundaries) = match (self.lightframe, other.lightframe) {
+            (Some(x), Some(y)) => {
+                let before = self.boundaries.map(|b| b.resolve(&x));
+                let after = other.boundaries.map(|b| b.resolve(&y));
+
+                // Connect the trails of the last frame of `self` and the first frame of `other`
+                let x = match (&before, &after, settings.gap_filling) {
+                    (Some((_, last)), Some((first, _)), Some(max_gap)) => x.fill_gap(last, first, max_gap)?,
+                    _ => x,
+                };
+
+                // Only the outer boundaries are kept, such that interior frames are dropped
+                let boundaries = match (before, after) {
+                    (Some((first, _)), Some((_, last))) => Some(Boundaries::Range(first, last)),
+                    (Some((first, last)), None) | (None, Some((first, last))) => Some(Boundaries::Range(first, last)),
+                    (None, None) => None,
+                };
+
+                (Some(Frame::count_and_merge(x, y, MergeMode::Maximize, state.clone())?), boundaries)
+            }
+            (x, y) => (x.or(y), self.boundaries.or(other.boundaries)),
+        };
+
+        let frame = Frame {
+            lightframe,
+            darkframe: Frame::average(self.darkframe, other.darkframe, state.clone())?,
+            bias: Frame::average(self.bias, other.bias, state.clone())?,
+            flat: Frame::average(self.flat, other.flat, state.clone())?,
+            dark_flat: Frame::average(self.dark_flat, other.dark_flat, state.clone())?,
+            boundaries,
+            stack: match (self.stack, other.stack) {
+                (Some(x), Some(y)) if count_stack => {
+                    state.lock().unwrap().start_merging();
+                    let stack = x.merge(y);
+                    state.lock().unwrap().finish_merging();
+                    Some(stack?)
+                }
+                (Some(x), Some(y)) => Some(x.merge(y)?),
                 (Some(x), None) => Some(x),
                 (None, Some(y)) => Some(y),
                 (None, None) => None,
             },
+            mask: self.mask.or(other.mask),
         };
 
         Ok(Box::new(frame))
     }
 
+    /// Fades the merged lightframes by the factor, only in the sky if the comets fade only there.
+    pub fn fade(self, factor: f32, settings: &MergeSettings) -> anyhow::Result<Frame> {
+        let lightframe = match (self.lightframe, &self.mask, settings.fade_sky_only) {
+            (Some(x), Some(mask), true) => Some(x.attenuate_sky(factor, mask)?),
+            (Some(x), _, _) => Some(x.attenuate(factor)),
+            (None, _, _) => None,
+        };
+
+        Ok(Frame { lightframe, ..self })
+    }
+
+    /// Number of lightframes in the maximized lightframe.
+    pub fn num_images(&self) -> usize {
+        self.lightframe.as_ref().map_or(0, Image::num_images)
+    }
+
+    /// Number of merges that are counted when merging the two frames.
+    pub fn count_merges(&self, other: &Frame) -> usize {
+        let both = |x: bool, y: bool| (x && y) as usize;
+        let lightframes = both(self.lightframe.is_some(), other.lightframe.is_some());
+        let calibration = both(self.darkframe.is_some(), other.darkframe.is_some())
+            + both(self.bias.is_some(), other.bias.is_some())
+            + both(self.flat.is_some(), other.flat.is_some())
+            + both(self.dark_flat.is_some(), other.dark_flat.is_some());
+
+        // The lightframes and the stack of composites count as a single merge
+        match lightframes {
+            0 => both(self.stack.is_some(), other.stack.is_some()) + calibration,
+            _ => lightframes + calibration,
+        }
+    }
+
+    /// Averages the calibration frames of the same type.
+    fn average(
+        x: Option<Accumulator>,
+        y: Option<Accumulator>,
+        state: Arc<Mutex<status::ProcessingStatus>>,
+    ) -> anyhow::Result<Option<Accumulator>> {
+        match (x, y) {
+            (Some(x), Some(y)) => {
+                state.lock().unwrap().start_merging();
+                let average = x.merge(y);
+                state.lock().unwrap().finish_merging();
+                Ok(Some(average?))
+            }
+            (x, None) => Ok(x),
+            (None, y) => Ok(y),
+        }
+    }
+
     fn count_and_merge(
         x: Image,
         y: Image,
@@ -94,6 +466,7 @@ impl Frame {
     }
 }
 
+#[derive(Clone)]
 pub struct Image {
     raw_image: RawImage,
     pub exif: Exif,
@@ -101,58 +474,363 @@ pub struct Image {
 }
 
 impl Image {
-    pub fn apply_darkframe(self, darkframe: Image) -> anyhow::Result<Image> {
+    /// Subtracts the noise of the darkframe, keeping the black level of every CFA channel.
+    ///
+    /// The darkframe consists of the readout offset and the thermal noise above it. Only the thermal noise is scaled to
+    /// the lightframe, which returns the factor that was used. The offset is the bias, which keeps its average per
+    /// channel, or else the black levels of the darkframe.
+    pub fn apply_darkframe(
+        self,
+        darkframe: Image,
+        bias: Option<&Image>,
+        scaling: Option<DarkScaling>,
+    ) -> anyhow::Result<(Image, f32)> {
         info!("Applying darkframe...");
+        self.ensure_same_size(&darkframe, "darkframe")?;
 
-        anyhow::ensure!(
-            self.raw_image.width == darkframe.raw_image.width
-                && self.raw_image.height == darkframe.raw_image.height
-                && self.raw_image.cpp == darkframe.raw_image.cpp,
-            "Lightframe and darkframe have different dimensions."
-        );
+        let light = self.image_data()?;
+        let dark = darkframe.image_data()?;
 
-        let light = self.get_image_data()?;
-        let dark = darkframe.get_image_data()?;
-        let avg_black = (dark.iter().fold(0u64, |acc, x| acc + *x as u64) / dark.len() as u64) as i16;
+        // The level that is kept is the average of the bias per channel, which replaces the pattern of the offset
+        let (kept, thermal): (Vec<f32>, Vec<f32>) = match bias {
+            Some(bias) => {
+                self.ensure_same_size(bias, "bias frame")?;
+                let bias = bias.image_data()?;
+                let (_, channel) = self.channels();
+                let means = self.channel_means(bias.iter().map(|x| *x as f32));
+
+                bias.iter()
+                    .zip(dark)
+                    .enumerate()
+                    .map(|(i, (b, d))| (means[channel(i)], *d as f32 - *b as f32))
+                    .unzip()
+            }
+            None => {
+                let black = BlackLevels::new(&darkframe.raw_image, dark);
+                dark.iter()
+                    .enumerate()
+                    .map(|(i, d)| (black.at(i), *d as f32 - black.at(i)))
+                    .unzip()
+            }
+        };
 
+        let scale = match scaling {
+            Some(scaling) => {
+                let scale = scaling.scale(
+                    light,
+                    &thermal,
+                    &self.sample_layout(),
+                    self.frame_exposure(),
+                    darkframe.frame_exposure(),
+                );
+                info!("Scaling the darkframe by {:.3}", scale);
+                scale
+            }
+            None => 1.0,
+        };
+
+        // The whole darkframe is subtracted, and the thermal noise once more by the scaling beyond it
         let res = light
             .iter()
             .zip(dark)
-            .map(|(x, y)| max(0, *x as i16 - (y as i16 - avg_black)) as u16)
+            .zip(kept.iter().zip(&thermal))
+            .map(|((x, d), (k, t))| {
+                (*x as f32 - *d as f32 + k - (scale - 1.0) * t)
+                    .round()
+                    .clamp(0.0, u16::MAX as f32) as u16
+            })
             .collect();
 
-        Ok(Image {
-            raw_image: RawImage {
-                camera: self.raw_image.camera,
-                make: self.raw_image.make,
-                model: self.raw_image.model,
-                clean_make: self.raw_image.clean_make,
-                clean_model: self.raw_image.clean_model,
-                width: self.raw_image.width,
-                height: self.raw_image.height,
-                cpp: self.raw_image.cpp,
-                bps: self.raw_image.bps,
-                wb_coeffs: self.raw_image.wb_coeffs,
-                whitelevel: self.raw_image.whitelevel,
-                blacklevel: self.raw_image.blacklevel,
-                xyz_to_cam: self.raw_image.xyz_to_cam,
-                cfa: self.raw_image.cfa,
-                active_area: self.raw_image.active_area,
-                crop_area: self.raw_image.crop_area,
-                blackareas: self.raw_image.blackareas,
-                orientation: self.raw_image.orientation,
-                data: RawImageData::Integer(res),
-                color_matrix: self.raw_image.color_matrix,
-                dng_tags: self.raw_image.dng_tags,
-            },
-            exif: self.exif,
-            num_images: self.num_images,
-        })
+        Ok((self.with_data(res), scale))
+    }
+
+    /// Subtracts the pattern of the readout offset, keeping its average per CFA channel.
+    pub fn apply_bias(self, bias: &Image) -> anyhow::Result<Image> {
+        info!("Applying bias frame...");
+        self.ensure_same_size(bias, "bias frame")?;
+
+        let data = bias.image_data()?;
+        let (_, channel) = self.channels();
+        let means = self.channel_means(data.iter().map(|x| *x as f32));
+        let res = self
+            .image_data()?
+            .iter()
+            .zip(data)
+            .enumerate()
+            .map(|(i, (x, b))| {
+                (*x as f32 - (*b as f32 - means[channel(i)]))
+                    .round()
+                    .clamp(0.0, u16::MAX as f32) as u16
+            })
+            .collect();
+
+        Ok(self.with_data(res))
+    }
+
+    /// Divides out the vignetting of the flat frame, which is normalised per CFA channel.
+    ///
+    /// The offset of the flat is subtracted first, which is the given dark flat or bias, or the black level.
+    pub fn apply_flat(self, flat: &Image, offset: Option<&Image>) -> anyhow::Result<Image> {
+        info!("Applying flat frame...");
+        self.ensure_same_size(flat, "flat frame")?;
+
+        let signal: Vec<f32> = match offset {
+            Some(offset) => {
+                self.ensure_same_size(offset, "offset of the flat frame")?;
+                flat.image_data()?
+                    .iter()
+                    .zip(offset.image_data()?)
+                    .map(|(x, o)| *x as f32 - *o as f32)
+                    .collect()
+            }
+            None => {
+                let data = flat.image_data()?;
+                let black = BlackLevels::new(&flat.raw_image, data);
+                data.iter().enumerate().map(|(i, x)| *x as f32 - black.at(i)).collect()
+            }
+        };
+
+        // The flat is normalised per channel, such that it doesn't change the white balance
+        let (_, channel) = self.channels();
+        let means = self.channel_means(signal.iter().copied());
+
+        let light = self.image_data()?;
+        let black = BlackLevels::new(&self.raw_image, light);
+        let res = light
+            .iter()
+            .zip(&signal)
+            .enumerate()
+            .map(|(i, (x, s))| {
+                // Samples without signal in the flat, like dead pixels, are left unchanged
+                let gain = if *s >= 1.0 { means[channel(i)] / s } else { 1.0 };
+                (black.at(i) + (*x as f32 - black.at(i)) * gain)
+                    .round()
+                    .clamp(0.0, u16::MAX as f32) as u16
+            })
+            .collect();
+
+        Ok(self.with_data(res))
     }
 
-    fn get_image_data(&self) -> anyhow::Result<Vec<u16>> {
+    /// Returns the number of color channels and a function that maps a sample to its channel.
+    ///
+    /// CFA images have a channel per position in the pattern, such that both greens of a Bayer pattern are separate.
+    fn channels(&self) -> (usize, impl Fn(usize) -> usize) {
+        let (width, cpp) = (self.raw_image.width, self.raw_image.cpp);
+        let (cfa_width, cfa_height) = match cpp {
+            1 => (self.raw_image.cfa.width.max(1), self.raw_image.cfa.height.max(1)),
+            _ => (1, 1),
+        };
+
+        let channel = move |i: usize| {
+            let (pixel, sample) = (i / cpp, i % cpp);
+            let (x, y) = (pixel % width, pixel / width);
+            ((y % cfa_height) * cfa_width + x % cfa_width) * cpp + sample
+        };
+
+        (cfa_width * cfa_height * cpp, channel)
+    }
+
+    /// Averages the values of the samples per channel.
+    fn channel_means(&self, values: impl Iterator<Item = f32>) -> Vec<f32> {
+        let (num_channels, channel) = self.channels();
+        let mut sums = vec![(0.0f64, 0usize); num_channels];
+        for (i, x) in values.enumerate() {
+            let sum = &mut sums[channel(i)];
+            sum.0 += x as f64;
+            sum.1 += 1;
+        }
+
+        sums.iter().map(|(sum, n)| (sum / (*n).max(1) as f64) as f32).collect()
+    }
+
+    /// Average black level over all channels.
+    fn black_level(&self) -> f32 {
+        let levels = &self.raw_image.blacklevel.levels;
+        levels.iter().map(|x| x.as_f32()).sum::<f32>() / levels.len().max(1) as f32
+    }
+
+    fn ensure_same_size(&self, other: &Image, name: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(self.size() == other.size(), "Lightframe and {} have different dimensions.", name);
+        Ok(())
+    }
+
+    /// Fills the gap between the trails of two consecutive lightframes by adding their connections to this image.
+    pub fn fill_gap(mut self, previous: &Image, next: &Image, max_gap: usize) -> anyhow::Result<Image> {
+        anyhow::ensure!(
+            previous.raw_image.width == next.raw_image.width
+                && previous.raw_image.height == next.raw_image.height
+                && previous.raw_image.cpp == next.raw_image.cpp,
+            "Consecutive lightframes have different dimensions."
+        );
+
+        let bridge = gap_filling::bridge(previous.image_data()?, next.image_data()?, &next.sample_layout(), max_gap);
+
+        let res = self.image_data()?.iter().zip(bridge).map(|(x, y)| max(*x, y)).collect();
+        self.raw_image.data = RawImageData::Integer(res);
+
+        Ok(self)
+    }
+
+    /// Takes the sky from this image and the foreground from the other one, blended by the weights of the mask.
+    pub fn composite(self, foreground: Image, mask: &SkyMask) -> anyhow::Result<Image> {
+        self.ensure_mask_size(mask)?;
+
+        let cpp = self.raw_image.cpp;
+        let res = self
+            .image_data()?
+            .iter()
+            .zip(foreground.image_data()?)
+            .enumerate()
+            .map(|(i, (sky, ground))| {
+                let weight = mask.weight(i / cpp);
+                (*sky as f32 * weight + *ground as f32 * (1.0 - weight)).round() as u16
+            })
+            .collect();
+
+        Ok(self.with_data(res))
+    }
+
+    pub fn ensure_mask_size(&self, mask: &SkyMask) -> anyhow::Result<()> {
+        ensure_mask_size(&self.raw_image, mask)
+    }
+
+    /// Checks that the mask fits the frames of the file, which is decoded without its image data.
+    pub fn ensure_file_mask_size(path: &Path, mask: &SkyMask) -> anyhow::Result<()> {
+        ensure_mask_size(&Image::read_dimensions(path)?, mask)
+    }
+
+    /// Finds streaks that appear in this frame, but not in the neighbouring frames.
+    pub fn find_streaks(
+        &self,
+        previous: Option<&Image>,
+        next: Option<&Image>,
+        settings: &StreakDetection,
+    ) -> anyhow::Result<Streaks> {
+        let neighbours = self.neighbour_data(previous, next)?;
+        if neighbours.is_empty() {
+            return Ok(Streaks {
+                mask: Vec::new(),
+                regions: Vec::new(),
+            });
+        }
+
+        Ok(transients::detect(self.image_data()?, &neighbours, &self.sample_layout(), settings))
+    }
+
+    /// Replaces the streaks of satellites and planes by the darker value of the neighbouring frames.
+    ///
+    /// Returns the cleaned image and the number of replaced streaks, if there were any.
+    pub fn remove_transients(
+        &self,
+        previous: Option<&Image>,
+        next: Option<&Image>,
+        settings: &StreakDetection,
+    ) -> anyhow::Result<Option<(Image, usize)>> {
+        let streaks = self.find_streaks(previous, next, settings)?;
+        if streaks.regions.is_empty() {
+            return Ok(None);
+        }
+
+        let neighbours = self.neighbour_data(previous, next)?;
+        let res = self
+            .image_data()?
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                if streaks.mask[i] {
+                    neighbours.iter().map(|n| n[i]).min().unwrap_or(*x)
+                } else {
+                    *x
+                }
+            })
+            .collect();
+
+        Ok(Some((self.clone().with_data(res), streaks.regions.len())))
+    }
+
+    fn neighbour_data<'a>(
+        &self,
+        previous: Option<&'a Image>,
+        next: Option<&'a Image>,
+    ) -> anyhow::Result<Vec<&'a [u16]>> {
+        let neighbours = previous.into_iter().chain(next).collect::<Vec<&Image>>();
+        anyhow::ensure!(
+            neighbours.iter().all(|n| n.size() == self.size()),
+            "Consecutive lightframes have different dimensions."
+        );
+
+        neighbours.iter().map(|n| n.image_data()).collect()
+    }
+
+    /// Measures the brightness above the black level, to compare the frame with its predecessors.
+    pub fn statistics(&self) -> anyhow::Result<FrameStatistics> {
+        Ok(frame_quality::measure(self.image_data()?, &self.sample_layout(), self.black_level()))
+    }
+
+    /// Describes the layout of the samples, where only CFA images need to skip the pixels of other colors.
+    fn sample_layout(&self) -> SampleLayout {
+        let (step_x, step_y) = match self.raw_image.cpp {
+            1 => (self.raw_image.cfa.width, self.raw_image.cfa.height),
+            _ => (1, 1),
+        };
+
+        SampleLayout {
+            row_length: self.raw_image.width * self.raw_image.cpp,
+            step_x,
+            step_y,
+            cpp: self.raw_image.cpp,
+        }
+    }
+
+    /// Replaces the image data, keeping the metadata.
+    pub fn with_data(mut self, data: Vec<u16>) -> Image {
+        self.raw_image.data = RawImageData::Integer(data);
+        self
+    }
+
+    /// Exposure time of a single frame in seconds, as the exposure times add up when merging.
+    pub fn frame_exposure(&self) -> Option<f32> {
+        self.exif
+            .exposure_time
+            .filter(|x| x.d > 0)
+            .map(|x| x.n as f32 / x.d as f32 / self.num_images.max(1) as f32)
+    }
+
+    pub fn num_images(&self) -> usize {
+        self.num_images
+    }
+
+    pub fn capture_time(&self) -> Option<NaiveDateTime> {
+        fileinfo::capture_time(&self.exif)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match &self.raw_image.data {
+            RawImageData::Integer(d) => d.len() * std::mem::size_of::<u16>(),
+            RawImageData::Float(d) => d.len() * std::mem::size_of::<f32>(),
+        }
+    }
+
+    /// Sets the number of merged frames, e.g. for a master that was loaded from a file.
+    pub fn with_num_images(mut self, num_images: usize) -> Image {
+        self.num_images = num_images;
+        self
+    }
+
+    /// Returns the make and model of the camera.
+    pub fn camera(&self) -> String {
+        format!("{} {}", self.raw_image.clean_make, self.raw_image.clean_model)
+    }
+
+    /// Returns the width, height and samples per pixel of the raw data.
+    pub fn size(&self) -> (usize, usize, usize) {
+        (self.raw_image.width, self.raw_image.height, self.raw_image.cpp)
+    }
+
+    pub fn image_data(&self) -> anyhow::Result<&[u16]> {
         if let rawler::RawImageData::Integer(data) = &self.raw_image.data {
-            Ok(data.clone())
+            Ok(data)
         } else {
             anyhow::bail!("Can't parse RAWs with non-integer data, yet.");
         }
@@ -167,23 +845,146 @@ impl Image {
         // Decode the file
         let raw_params = RawDecodeParams { image_index: 0 };
         let metadata = decoder.raw_metadata(&mut rawfile, raw_params.clone())?;
-        let mut raw_image = decoder.raw_image(&mut rawfile, raw_params.clone(), false)?;
+        let raw_image = decoder.raw_image(&mut rawfile, raw_params.clone(), false)?;
+
+        let image = Image {
+            raw_image,
+            exif: metadata.exif,
+            num_images: 1,
+        };
+
+        Ok(image.attenuate(intensity))
+    }
+
+    /// Divides the rows of the raw file into horizontal strips, whose borders are aligned to the CFA and black level
+    /// patterns.
+    pub fn strip_rows(path: &Path, strips: usize) -> anyhow::Result<Vec<Range<usize>>> {
+        let raw_image = Image::read_dimensions(path)?;
+        let alignment = raw_image.cfa.height.max(1) * raw_image.blacklevel.height.max(1);
+        let (height, strips) = (raw_image.height, strips.max(1));
+
+        let borders: Vec<usize> = (0..=strips)
+            .map(|i| match i {
+                i if i == strips => height,
+                i => i * height / strips / alignment * alignment,
+            })
+            .collect();
+
+        Ok(borders
+            .windows(2)
+            .map(|x| x[0]..x[1])
+            .filter(|x| !x.is_empty())
+            .collect())
+    }
+
+    /// Reads the dimensions and the metadata of a raw file without decoding the image data.
+    fn read_dimensions(path: &Path) -> anyhow::Result<RawImage> {
+        let file_buffer = BufReader::new(File::open(path)?);
+        let mut rawfile = RawFile::new(path, file_buffer);
+        let decoder = rawler::get_decoder(&mut rawfile)?;
+
+        decoder
+            .raw_image(&mut rawfile, RawDecodeParams { image_index: 0 }, true)
+            .with_context(|| format!("Could not load file {:#?}", path))
+    }
+
+    /// Keeps only the given rows, such that the rest of the frame can be freed.
+    pub fn strip(self, rows: Range<usize>) -> anyhow::Result<Image> {
+        anyhow::ensure!(rows.end <= self.raw_image.height, "The strip exceeds the height of the frame");
+
+        let row_length = self.raw_image.width * self.raw_image.cpp;
+        let data = self.image_data()?[rows.start * row_length..rows.end * row_length].to_vec();
+        let mut image = self.with_data(data);
+        image.raw_image.height = rows.len();
+
+        Ok(image)
+    }
+
+    /// Joins horizontal strips from top to bottom, keeping the metadata of the first one.
+    pub fn join_strips(strips: Vec<Image>) -> anyhow::Result<Image> {
+        let mut strips = strips.into_iter();
+        let first = match strips.next() {
+            Some(x) => x,
+            None => anyhow::bail!("There are no strips to join"),
+        };
+
+        let mut data = first.image_data()?.to_vec();
+        let mut height = first.raw_image.height;
+        for strip in strips {
+            anyhow::ensure!(
+                strip.raw_image.width == first.raw_image.width && strip.raw_image.cpp == first.raw_image.cpp,
+                "Strips to join have different widths"
+            );
+            data.extend_from_slice(strip.image_data()?);
+            height += strip.raw_image.height;
+        }
+
+        let mut image = first.with_data(data);
+        image.raw_image.height = height;
 
+        Ok(image)
+    }
+
+    /// Scales the brightness of the image data by `intensity`, leaving the metadata untouched.
+    pub fn attenuate(mut self, intensity: f32) -> Image {
         // Apply intensity if applicable
         if (intensity - 1.0).abs() > 0.001 {
-            raw_image.data = match raw_image.data {
+            self.raw_image.data = match self.raw_image.data {
                 RawImageData::Integer(d) => {
                     RawImageData::Integer(d.iter().map(|x| (*x as f32 * intensity) as u16).collect())
                 }
-                RawImageData::Float(d) => RawImageData::Float(d.iter().map(|x| (*x as f32 * intensity)).collect()),
+                RawImageData::Float(d) => RawImageData::Float(d.iter().map(|x| *x * intensity).collect()),
             };
         }
 
-        Ok(Image {
-            raw_image,
-            exif: metadata.exif,
-            num_images: 1,
-        })
+        self
+    }
+
+    /// Scales the brightness by `intensity` in the sky of the mask, keeping the foreground at full brightness.
+    pub fn attenuate_sky(self, intensity: f32, mask: &SkyMask) -> anyhow::Result<Image> {
+        if (intensity - 1.0).abs() <= 0.001 {
+            return Ok(self);
+        }
+        self.ensure_mask_size(mask)?;
+
+        let cpp = self.raw_image.cpp;
+        let res = self
+            .image_data()?
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (*x as f32 * (1.0 - mask.weight(i / cpp) * (1.0 - intensity))) as u16)
+            .collect();
+
+        Ok(self.with_data(res))
+    }
+
+    /// Writes the image as lossless DNG without a preview into the directory, which keeps the data and metadata but not
+    /// the number of images.
+    pub fn save(self, directory: &Path, name: &str) -> anyhow::Result<ImagePart> {
+        let part = ImagePart {
+            name: name.to_string(),
+            num_images: self.num_images,
+        };
+        ImageWriter::raw(self.raw_image, self.exif).write_dng(part.path(directory, "dng"))?;
+
+        Ok(part)
+    }
+
+    /// Writes only the metadata of parts that store their samples in separate files.
+    ///
+    /// The DNG keeps the dimensions, but its samples are blank, such that they compress to almost nothing.
+    pub fn save_metadata(&self, directory: &Path, name: &str) -> anyhow::Result<ImagePart> {
+        let (width, height, cpp) = self.size();
+        self.clone()
+            .with_data(vec![0; width * height * cpp])
+            .save(directory, name)
+    }
+
+    pub fn load(directory: &Path, part: &ImagePart) -> anyhow::Result<Image> {
+        let path = part.path(directory, "dng");
+        let image = Image::from_raw_file(&path, 1.0).with_context(|| format!("Could not load {:#?}", path))?;
+
+        Ok(image.with_num_images(part.num_images))
     }
 
     pub fn get_image_writer(self) -> anyhow::Result<ImageWriter> {
@@ -237,7 +1038,7 @@ impl Mergable for RawImage {
             .map(|(x, y)| match mode {
                 MergeMode::Maximize => max(*x, y),
                 MergeMode::WeightedAverage => {
-                    ((*x as f32 * weight_self + y as f32 * weight_other) / (weight_self + weight_other)) as u16
+                    ((*x as f32 * weight_self + y as f32 * weight_other) / (weight_self + weight_other)).round() as u16
                 }
             })
             .collect();
@@ -386,3 +1187,198 @@ fn max_exif_entry<T: std::cmp::Ord>(entry1: Option<T>, entry2: Option<T>) -> Opt
 
     Some(std::cmp::max(x1, x2))
 }
+
+fn ensure_mask_size(raw_image: &RawImage, mask: &SkyMask) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        raw_image.width == mask.width && raw_image.height == mask.height,
+        "The mask has a size of {}x{}, but the images have {}x{} pixels.",
+        mask.width,
+        mask.height,
+        raw_image.width,
+        raw_image.height
+    );
+    Ok(())
+}
+
+/// Joins a part that every strip has, or none of them.
+pub fn join_parts<T>(
+    parts: Vec<Option<T>>,
+    join: impl FnOnce(Vec<T>) -> anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    match parts.iter().filter(|x| x.is_some()).count() {
+        0 => Ok(None),
+        n if n == parts.len() => Ok(Some(join(parts.into_iter().flatten().collect())?)),
+        _ => anyhow::bail!("The strips to join consist of different parts"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::stacking::SumStack;
+    use rawler::imgop::{Dim2, Point, Rect};
+    use rawler::rawimage::BlackLevel;
+    use rawler::{Orientation, CFA};
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 4;
+
+    /// Black levels of the RGGB pattern, which differ per channel
+    const BLACK: [u32; 4] = [512, 1024, 1000, 2048];
+
+    fn synthetic(data: Vec<u16>, black: &[u32], blackareas: Vec<Rect>) -> Image {
+        let raw_image = RawImage {
+            camera: Default::default(),
+            make: String::from("Synthetic"),
+            model: String::from("Sensor"),
+            clean_make: String::from("Synthetic"),
+            clean_model: String::from("Sensor"),
+            width: WIDTH,
+            height: HEIGHT,
+            cpp: 1,
+            bps: 16,
+            wb_coeffs: [1.0, 1.0, 1.0, f32::NAN],
+            whitelevel: vec![u16::MAX],
+            blacklevel: BlackLevel {
+                levels: black.iter().map(|x| tiff::Rational::new(*x, 1)).collect(),
+                width: if black.len() == 4 { 2 } else { 1 },
+                height: if black.len() == 4 { 2 } else { 1 },
+                cpp: 1,
+            },
+            xyz_to_cam: Default::default(),
+            cfa: CFA::new("RGGB"),
+            active_area: None,
+            crop_area: None,
+            blackareas,
+            orientation: Orientation::Normal,
+            data: RawImageData::Integer(data),
+            color_matrix: Default::default(),
+            dng_tags: Default::default(),
+        };
+
+        Image {
+            raw_image,
+            exif: Exif::default(),
+            num_images: 1,
+        }
+    }
+
+    /// Creates the data of a frame, where the value of each sample depends on its channel and position.
+    fn samples(value: impl Fn(usize, usize, usize) -> u32) -> Vec<u16> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                value((y % 2) * 2 + x % 2, x, y) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn darkframe_keeps_black_level_per_channel() {
+        let hot = |x, y| if (x, y) == (1, 1) { 300 } else { 10 };
+        let light = synthetic(samples(|c, x, y| BLACK[c] + 100 + hot(x, y)), &BLACK, Vec::new());
+        let dark = synthetic(samples(|c, x, y| BLACK[c] + hot(x, y)), &BLACK, Vec::new());
+
+        let (res, scale) = light.apply_darkframe(dark, None, None).unwrap();
+
+        assert_eq!(scale, 1.0);
+        assert_eq!(res.image_data().unwrap(), &samples(|c, _, _| BLACK[c] + 100)[..]);
+    }
+
+    #[test]
+    fn flat_keeps_black_level_per_channel() {
+        // The left half of the optics transmits half the light of the right half
+        let vignetting = |x: usize| if x < 2 { 100 } else { 200 };
+        let flat = synthetic(samples(|c, x, _| BLACK[c] + vignetting(x)), &BLACK, Vec::new());
+        let light = synthetic(samples(|c, x, _| BLACK[c] + vignetting(x) / 2), &BLACK, Vec::new());
+
+        let res = light.apply_flat(&flat, None).unwrap();
+
+        assert_eq!(res.image_data().unwrap(), &samples(|c, _, _| BLACK[c] + 75)[..]);
+    }
+
+    #[test]
+    fn darkframe_does_not_overflow() {
+        let light = synthetic(samples(|c, x, _| if x < 2 { 60000 } else { 100 + c as u32 }), &[0], Vec::new());
+        let dark = synthetic(samples(|_, x, _| if x < 2 { 50 } else { 40000 }), &[0], Vec::new());
+
+        let (res, _) = light.apply_darkframe(dark, None, None).unwrap();
+
+        assert_eq!(res.image_data().unwrap(), &samples(|_, x, _| if x < 2 { 59950 } else { 0 })[..]);
+    }
+
+    #[test]
+    fn darkframe_measures_masked_areas() {
+        // The first two columns are masked and show an offset of 600, while the metadata declares none
+        let masked = vec![Rect::new(Point::new(0, 0), Dim2::new(2, HEIGHT))];
+        let light = synthetic(samples(|_, x, _| if x < 2 { 600 } else { 820 }), &[0], masked.clone());
+        let dark = synthetic(samples(|_, x, _| if x < 2 { 600 } else { 620 }), &[0], masked);
+
+        let (res, _) = light.apply_darkframe(dark, None, None).unwrap();
+
+        assert_eq!(res.image_data().unwrap(), &samples(|_, x, _| if x < 2 { 600 } else { 800 })[..]);
+    }
+
+    #[test]
+    fn darkframe_keeps_bias_per_channel() {
+        // The pattern differs between the samples of each channel
+        let pattern = |x: usize, y: usize| (2 * x + y) as u32;
+        let bias = synthetic(samples(|c, x, y| BLACK[c] + pattern(x, y)), &BLACK, Vec::new());
+        let light = synthetic(samples(|c, x, y| BLACK[c] + pattern(x, y) + 30 + 100), &BLACK, Vec::new());
+        let dark = synthetic(samples(|c, x, y| BLACK[c] + pattern(x, y) + 30), &BLACK, Vec::new());
+
+        let (res, _) = light.apply_darkframe(dark, Some(&bias), None).unwrap();
+
+        // The pattern is removed, while its average over the two samples of the channel per axis is kept
+        let average = |x: usize, y: usize| pattern(x % 2 + 1, y % 2 + 1);
+        assert_eq!(res.image_data().unwrap(), &samples(|c, x, y| BLACK[c] + average(x, y) + 100)[..]);
+    }
+
+    #[test]
+    fn darkframe_scaled_by_exposure() {
+        let mut light = synthetic(samples(|c, _, _| BLACK[c] + 100 + 20), &BLACK, Vec::new());
+        let mut dark = synthetic(samples(|c, _, _| BLACK[c] + 10), &BLACK, Vec::new());
+        light.exif.exposure_time = Some(tiff::Rational::new(30, 1));
+        dark.exif.exposure_time = Some(tiff::Rational::new(15, 1));
+
+        let (res, scale) = light.apply_darkframe(dark, None, Some(DarkScaling::Exposure)).unwrap();
+
+        assert_eq!(scale, 2.0);
+        assert_eq!(res.image_data().unwrap(), &samples(|c, _, _| BLACK[c] + 100)[..]);
+    }
+
+    #[test]
+    fn darkframe_scales_only_thermal_noise() {
+        let bias = synthetic(samples(|c, _, _| BLACK[c] + 8), &BLACK, Vec::new());
+        let mut light = synthetic(samples(|c, _, _| BLACK[c] + 8 + 2 * 10 + 100), &BLACK, Vec::new());
+        let mut dark = synthetic(samples(|c, _, _| BLACK[c] + 8 + 10), &BLACK, Vec::new());
+        light.exif.exposure_time = Some(tiff::Rational::new(30, 1));
+        dark.exif.exposure_time = Some(tiff::Rational::new(15, 1));
+
+        let (res, _) = light
+            .apply_darkframe(dark, Some(&bias), Some(DarkScaling::Exposure))
+            .unwrap();
+
+        // Scaling the whole darkframe would remove the offset of the bias twice
+        assert_eq!(res.image_data().unwrap(), &samples(|c, _, _| BLACK[c] + 8 + 100)[..]);
+    }
+
+    #[test]
+    fn joined_strips_keep_their_stacks() {
+        let first = synthetic(samples(|c, x, y| BLACK[c] + (x + 4 * y) as u32), &BLACK, Vec::new());
+        let second = synthetic(samples(|c, x, y| BLACK[c] + 2 * (x + 4 * y) as u32 + 7), &BLACK, Vec::new());
+        let stack = |image: Image| Stack::Sum(SumStack::from_image(image, true).unwrap());
+        let merged = |x: &Image, y: &Image| stack(x.clone()).merge(stack(y.clone())).unwrap();
+
+        let strips = vec![0..2, 2..4]
+            .into_iter()
+            .map(|rows| merged(&first.clone().strip(rows.clone()).unwrap(), &second.clone().strip(rows).unwrap()))
+            .collect();
+        let joined = Stack::join_strips(strips).unwrap();
+
+        assert_eq!(
+            joined.into_image().image_data().unwrap(),
+            merged(&first, &second).into_image().image_data().unwrap()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::append;
+use crate::processing::checkpoint::{FrameParts, MergeInputs};
+use crate::processing::comets::Comets;
+use crate::processing::image::Frame;
+use crate::processing::sky_mask::{ForegroundMode, MaskSource, SkyMaskSettings};
+use crate::processing::stacking::StackingMode;
+use crate::processing::{status, MergeSettings};
+
+/// First bytes of every intermediate file
+const MAGIC: &[u8; 8] = b"TRAWLSIF";
+const VERSION: u32 = 1;
+
+/// Header of an intermediate file, which is described in `docs/intermediate-format.md`
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    /// Number of lightframes in the frame
+    pub num_images: usize,
+    /// Files that were merged into the frame and the settings of the merge
+    #[serde(flatten)]
+    pub inputs: MergeInputs,
+    pub frame: FrameParts,
+    /// Files of the parts in the order their content follows the header
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub length: u64,
+}
+
+/// Checks whether a partial merge can be written with the settings, which have to give the same result for every
+/// grouping of the lightframes.
+///
+/// Comet tails are the only exception, as the tail of every part fades once more when the parts are combined. This
+/// rounds the faded samples once more, which may differ from a single merge by one.
+pub fn ensure_partial(settings: &MergeSettings) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        matches!(settings.comets, Comets::Normal | Comets::Tail { .. }),
+        "Comets that fade over the whole sequence can't be merged partially, as they depend on the position of every \
+         frame in the sequence"
+    );
+    anyhow::ensure!(
+        settings.stacking.passes() == 1,
+        "The clipping modes can't be merged partially, as their bounds depend on all frames"
+    );
+    anyhow::ensure!(
+        settings.stacking != StackingMode::Median && settings.composite != Some(ForegroundMode::Median),
+        "The median can't be merged partially, as it is estimated from samples that depend on the grouping of frames"
+    );
+    anyhow::ensure!(
+        !matches!(
+            settings.sky_mask,
+            Some(SkyMaskSettings {
+                source: MaskSource::Detect,
+                ..
+            })
+        ),
+        "Detected sky masks can't be merged partially, as they depend on all frames, export the mask and use it instead"
+    );
+    anyhow::ensure!(
+        settings.transients.is_none() && settings.rejection.is_none(),
+        "Transient removal and frame rejection can't be merged partially, as they compare frames with their neighbours"
+    );
+    anyhow::ensure!(settings.sequence.is_none(), "Sequences can't be merged partially");
+    anyhow::ensure!(
+        settings.append_to.is_none(),
+        "Partial merges can't be appended to an earlier result, combine them instead"
+    );
+
+    Ok(())
+}
+
+/// Writes the frame with its inputs into a single intermediate file.
+pub fn write(path: &Path, frame: &Frame, inputs: &MergeInputs) -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let parts = frame.save(directory.path())?;
+
+    let mut entries = fs::read_dir(directory.path())?
+        .map(|entry| {
+            let entry = entry?;
+            Ok(Entry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                length: entry.metadata()?.len(),
+            })
+        })
+        .collect::<anyhow::Result<Vec<Entry>>>()?;
+    entries.sort_by(|x, y| x.name.cmp(&y.name));
+
+    let header = Header {
+        version: VERSION,
+        num_images: parts.num_images(),
+        inputs: inputs.clone(),
+        frame: parts,
+        entries,
+    };
+    let header_bytes = serde_json::to_vec(&header)?;
+
+    info!("Writing intermediate file {:#?}...", path);
+    let file = File::create(path).with_context(|| format!("Could not create {:#?}", path))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(header_bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(&header_bytes)?;
+    for entry in &header.entries {
+        io::copy(&mut File::open(directory.path().join(&entry.name))?, &mut writer)?;
+    }
+
+    writer.flush().with_context(|| format!("Could not write {:#?}", path))
+}
+
+/// Loads the frame and the header of an intermediate file.
+pub fn read(path: &Path) -> anyhow::Result<(Frame, Header)> {
+    let file = File::open(path).with_context(|| format!("Could not open {:#?}", path))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic == MAGIC, "{:#?} is no intermediate file", path);
+
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    anyhow::ensure!(version == VERSION, "{:#?} has the unsupported version {}", path, version);
+
+    // The length isn't trusted, such that a damaged file can't allocate more than it holds
+    let mut length = [0u8; 8];
+    reader.read_exact(&mut length)?;
+    let length = u64::from_le_bytes(length);
+    let mut header_bytes = Vec::new();
+    (&mut reader).take(length).read_to_end(&mut header_bytes)?;
+    anyhow::ensure!(header_bytes.len() as u64 == length, "{:#?} is truncated", path);
+    let header: Header =
+        serde_json::from_slice(&header_bytes).with_context(|| format!("Invalid header of {:#?}", path))?;
+
+    // The parts are unpacked into a temporary directory, as they are loaded from files
+    let directory = tempfile::tempdir()?;
+    for entry in &header.entries {
+        anyhow::ensure!(
+            Path::new(&entry.name).file_name() == Some(OsStr::new(&entry.name)),
+            "{:#?} has an invalid entry {:#?}",
+            path,
+            entry.name
+        );
+
+        let mut output = File::create(directory.path().join(&entry.name))?;
+        let copied = io::copy(&mut (&mut reader).take(entry.length), &mut output)?;
+        anyhow::ensure!(copied == entry.length, "{:#?} is truncated", path);
+    }
+
+    let frame = Frame::load(directory.path(), &header.frame, None, None)?;
+    Ok((frame, header))
+}
+
+/// Checks whether the file starts like an intermediate file.
+pub fn is_intermediate(path: &Path) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut x| x.read_exact(&mut magic))
+        .map(|_| &magic == MAGIC)
+        .unwrap_or(false)
+}
+
+/// Merges the intermediate files in the order of the sequence, which have to be merged with the same settings.
+pub fn combine(
+    files: &[PathBuf],
+    state: Arc<Mutex<status::ProcessingStatus>>,
+) -> anyhow::Result<(Box<Frame>, MergeInputs)> {
+    state.lock().unwrap().add_tasks(files.len(), 0);
+
+    let mut combined: Option<(Box<Frame>, MergeInputs)> = None;
+    for path in files {
+        state.lock().unwrap().start_loading();
+        let (frame, header) = read(path)?;
+        state.lock().unwrap().finish_loading();
+
+        combined = Some(match combined {
+            Some((merged, mut inputs)) => {
+                ensure_compatible(&inputs, &header.inputs)
+                    .with_context(|| format!("{:#?} can't be combined with the preceding files", path))?;
+                let merged = append::merge_counted(merged, Box::new(frame), &inputs.settings, state.clone())?;
+
+                let (other, calibration) = (header.inputs, &mut inputs.calibration);
+                inputs.lightframes.extend(other.lightframes);
+                calibration.darkframes.extend(other.calibration.darkframes);
+                calibration.bias.extend(other.calibration.bias);
+                calibration.flats.extend(other.calibration.flats);
+                calibration.dark_flats.extend(other.calibration.dark_flats);
+                (merged, inputs)
+            }
+            None => (Box::new(frame), header.inputs),
+        });
+    }
+
+    combined.context("No intermediate files to combine")
+}
+
+/// Checks that two partial merges have the same settings, apart from how the merges were run.
+pub fn ensure_compatible(x: &MergeInputs, y: &MergeInputs) -> anyhow::Result<()> {
+    let (x, y) = (x.fingerprint()?, y.fingerprint()?);
+    if x == y {
+        return Ok(());
+    }
+
+    let differences: Vec<&str> = match (x.as_object(), y.as_object()) {
+        (Some(x), Some(y)) => x
+            .keys()
+            .chain(y.keys().filter(|k| !x.contains_key(*k)))
+            .filter(|k| x.get(*k) != y.get(*k))
+            .map(String::as_str)
+            .collect(),
+        _ => Vec::new(),
+    };
+    anyhow::bail!("The files were merged with different settings: {}", differences.join(", "))
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::image::Image;
+
+/// Type of a master calibration frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibrationType {
+    Dark,
+    Bias,
+    Flat,
+    DarkFlat,
+}
+
+impl CalibrationType {
+    fn file_stem(&self) -> &'static str {
+        match self {
+            CalibrationType::Dark => "master_dark",
+            CalibrationType::Bias => "master_bias",
+            CalibrationType::Flat => "master_flat",
+            CalibrationType::DarkFlat => "master_dark_flat",
+        }
+    }
+}
+
+/// Metadata of a master calibration frame, which is stored next to its DNG
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MasterInfo {
+    pub calibration_type: CalibrationType,
+    pub camera: String,
+    pub serial: Option<String>,
+    pub iso: Option<u32>,
+    /// Exposure time of a single frame in seconds
+    pub exposure: Option<f32>,
+    /// Number of frames that were averaged into the master
+    pub frame_count: usize,
+}
+
+impl MasterInfo {
+    pub fn describe(calibration_type: CalibrationType, image: &Image) -> MasterInfo {
+        let exif = &image.exif;
+
+        MasterInfo {
+            calibration_type,
+            camera: image.camera(),
+            serial: exif.serial_number.clone(),
+            iso: exif.iso_speed_ratings.map(|x| x as u32).or(exif.iso_speed),
+            exposure: exif
+                .exposure_time
+                .map(|x| x.as_f32() / image.num_images().max(1) as f32),
+            frame_count: image.num_images(),
+        }
+    }
+
+    /// Loads the metadata of a master, which is `None` for single frames without metadata.
+    pub fn load(path: &Path) -> anyhow::Result<Option<MasterInfo>> {
+        let sidecar = sidecar_path(path);
+        if !sidecar.is_file() {
+            return Ok(None);
+        }
+
+        let content =
+            fs::read_to_string(&sidecar).with_context(|| format!("Could not read calibration file {:#?}", sidecar))?;
+        let info =
+            serde_json::from_str(&content).with_context(|| format!("Invalid calibration file {:#?}", sidecar))?;
+
+        Ok(Some(info))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let sidecar = sidecar_path(path);
+        fs::write(&sidecar, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Could not write calibration file {:#?}", sidecar))
+    }
+}
+
+/// Restores the frame count of a master, such that it is weighted like its single frames when merged with further
+/// calibration frames.
+///
+/// The exposure time becomes the sum over all frames again, like for frames that were merged in this run.
+pub fn restore(image: Image, path: &Path, calibration_type: CalibrationType) -> anyhow::Result<Image> {
+    match MasterInfo::load(path)? {
+        Some(info) => {
+            anyhow::ensure!(
+                info.calibration_type == calibration_type,
+                "{:#?} is a master of type {:?}, but was given as {:?}",
+                path,
+                info.calibration_type,
+                calibration_type
+            );
+            let mut image = image.with_num_images(info.frame_count);
+            image.exif.exposure_time = image.exif.exposure_time.map(|x| {
+                let count = info.frame_count.max(1) as u32;
+                rawler::formats::tiff::Rational::new(x.n.saturating_mul(count), x.d)
+            });
+            Ok(image)
+        }
+        None => Ok(image),
+    }
+}
+
+/// Writes the master as DNG with its metadata into the directory and returns the path of the DNG.
+///
+/// The exposure time in the DNG is the one of a single frame, such that the master can be matched to lightframes.
+pub fn save(directory: &Path, calibration_type: CalibrationType, master: Image) -> anyhow::Result<PathBuf> {
+    let path = directory.join(format!("{}.dng", calibration_type.file_stem()));
+    let info = MasterInfo::describe(calibration_type, &master);
+
+    let mut master = master;
+    master.exif.exposure_time = master.exif.exposure_time.map(|x| {
+        let count = master.num_images().max(1) as u32;
+        rawler::formats::tiff::Rational::new(x.n, x.d.saturating_mul(count))
+    });
+
+    master.get_image_writer()?.write_dng(path.clone())?;
+    info.save(&path)?;
+
+    Ok(path)
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("calibration.json")
+}
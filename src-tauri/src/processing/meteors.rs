@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::transients::{BoundingBox, StreakDetection};
+
+/// Settings of the scan for meteors
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeteorScan {
+    pub detection: StreakDetection,
+    /// JSON or CSV file, depending on the extension
+    pub report: PathBuf,
+    /// Directory for cropped previews of the candidates
+    #[serde(default)]
+    pub previews: Option<PathBuf>,
+}
+
+/// Streak in a lightframe that may be a meteor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeteorCandidate {
+    pub path: PathBuf,
+    pub timestamp: Option<NaiveDateTime>,
+    pub bounding_box: BoundingBox,
+}
+
+const CSV_HEADER: &str = "path,timestamp,x,y,width,height";
+
+/// Writes the candidates as CSV if the file ends with `.csv`, otherwise as JSON.
+pub fn write_report(candidates: &[MeteorCandidate], path: &Path) -> anyhow::Result<()> {
+    let content = if is_csv(path) {
+        let rows = candidates.iter().map(|c| {
+            format!(
+                "\"{}\",{},{},{},{},{}",
+                c.path.display().to_string().replace('"', "\"\""),
+                c.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+                c.bounding_box.x,
+                c.bounding_box.y,
+                c.bounding_box.width,
+                c.bounding_box.height
+            )
+        });
+        std::iter::once(CSV_HEADER.to_string())
+            .chain(rows)
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else {
+        serde_json::to_string_pretty(candidates)?
+    };
+
+    fs::write(path, content).with_context(|| format!("Could not write meteor report {:#?}", path))
+}
+
+/// Reads the paths of the lightframes with meteors from a report, which may have been edited by hand.
+pub fn read_report(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read meteor report {:#?}", path))?;
+
+    if !is_csv(path) {
+        let candidates: Vec<MeteorCandidate> = serde_json::from_str(&content)?;
+        return Ok(candidates.into_iter().map(|c| c.path).collect());
+    }
+
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && *line != CSV_HEADER)
+        .map(|line| match line.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find("\",").context("Invalid quoting in meteor report")?;
+                Ok(PathBuf::from(quoted[..end].replace("\"\"", "\"")))
+            }
+            None => Ok(PathBuf::from(line.split(',').next().unwrap_or_default())),
+        })
+        .collect()
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension().map(|x| x.eq_ignore_ascii_case("csv")).unwrap_or(false)
+}
@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::checkpoint::{self, ImagePart, StackPart};
+use crate::processing::image::{Image, Mergable, MergeMode};
+
+/// Which value is taken from the sorted values of every pixel
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatistic {
+    /// The darkest value, removing transient lights
+    Minimum,
+    /// The median value, for a clean foreground
+    Median,
+    /// The mean of the given number of brightest values, for less noisy trails
+    Brightest(usize),
+}
+
+/// Keeps a bounded number of sorted values per pixel, which can be merged in any order.
+///
+/// Minimum and brightest values are exact, as only the darkest or brightest values are of interest. For the median,
+/// the values are reduced to evenly spaced quantiles once there are more frames than the capacity. Thus, the memory
+/// consumption does not depend on the number of frames.
+#[derive(Clone)]
+pub struct OrderStack {
+    /// Metadata of the stacked frames without image data
+    template: Image,
+    statistic: OrderStatistic,
+    /// Ascending values of all pixels, with `depth` consecutive values per pixel
+    values: Vec<u16>,
+    depth: usize,
+    capacity: usize,
+}
+
+impl OrderStack {
+    pub fn from_image(image: Image, statistic: OrderStatistic, median_samples: usize) -> anyhow::Result<OrderStack> {
+        let capacity = match statistic {
+            OrderStatistic::Minimum => 1,
+            OrderStatistic::Median => median_samples,
+            OrderStatistic::Brightest(count) => count,
+        };
+        anyhow::ensure!(capacity > 0, "At least one value per pixel has to be kept");
+
+        Ok(OrderStack {
+            values: image.image_data()?.to_vec(),
+            depth: 1,
+            capacity,
+            statistic,
+            template: image.with_data(Vec::new()),
+        })
+    }
+
+    /// Joins the stacks of horizontal strips from top to bottom, which hold the same number of values per pixel.
+    pub fn join_strips(strips: Vec<OrderStack>) -> anyhow::Result<OrderStack> {
+        let first = strips.first().context("There are no strips to join")?;
+        let (statistic, depth, capacity) = (first.statistic, first.depth, first.capacity);
+        anyhow::ensure!(
+            strips
+                .iter()
+                .all(|x| x.statistic == statistic && x.depth == depth && x.capacity == capacity),
+            "The strips to join keep different values per pixel"
+        );
+
+        let (templates, values): (Vec<Image>, Vec<Vec<u16>>) =
+            strips.into_iter().map(|x| (x.template, x.values)).unzip();
+        Ok(OrderStack {
+            template: Image::join_strips(templates)?,
+            statistic,
+            values: values.concat(),
+            depth,
+            capacity,
+        })
+    }
+
+    pub fn merge(self, other: OrderStack) -> anyhow::Result<OrderStack> {
+        anyhow::ensure!(
+            self.values.len() / self.depth == other.values.len() / other.depth,
+            "Images to stack have different dimensions"
+        );
+
+        let depth = (self.depth + other.depth).min(self.capacity);
+        let weight_self = self.template.num_images() as f32 / self.depth as f32;
+        let weight_other = other.template.num_images() as f32 / other.depth as f32;
+
+        let mut values = vec![0u16; self.values.len() / self.depth * depth];
+        values
+            .par_chunks_mut(depth)
+            .zip(
+                self.values
+                    .par_chunks(self.depth)
+                    .zip(other.values.par_chunks(other.depth)),
+            )
+            .for_each_init(Vec::new, |merged, (res, (x, y))| {
+                merge_sorted(merged, x, weight_self, y, weight_other);
+                match self.statistic {
+                    OrderStatistic::Minimum => copy_values(&merged[..depth], res),
+                    OrderStatistic::Brightest(_) => copy_values(&merged[merged.len() - depth..], res),
+                    OrderStatistic::Median => select_quantiles(merged, res),
+                }
+            });
+
+        Ok(OrderStack {
+            values,
+            depth,
+            capacity: self.capacity,
+            statistic: self.statistic,
+            template: self.template.merge(other.template, MergeMode::Maximize)?,
+        })
+    }
+
+    /// Bytes of the values once the stack holds its full number of values per pixel.
+    pub fn memory_size(&self) -> usize {
+        self.values.len() / self.depth * self.capacity * std::mem::size_of::<u16>()
+    }
+
+    /// Writes the sorted values of every pixel next to the current image.
+    pub fn save(&self, directory: &Path, name: &str) -> anyhow::Result<StackPart> {
+        let image = self.template.save_metadata(directory, name)?;
+        checkpoint::write_samples(&image.path(directory, "values"), &self.values)?;
+
+        Ok(StackPart::Order {
+            image,
+            statistic: self.statistic,
+            depth: self.depth,
+            capacity: self.capacity,
+        })
+    }
+
+    pub fn load(
+        directory: &Path,
+        image: &ImagePart,
+        statistic: OrderStatistic,
+        depth: usize,
+        capacity: usize,
+    ) -> anyhow::Result<OrderStack> {
+        let template = Image::load(directory, image)?;
+        anyhow::ensure!(depth > 0 && depth <= capacity, "Invalid depth of the stack {:#?}", image.name);
+        let values = checkpoint::read_samples(&image.path(directory, "values"), template.image_data()?.len() * depth)?;
+
+        Ok(OrderStack {
+            values,
+            depth,
+            capacity,
+            statistic,
+            template: template.with_data(Vec::new()),
+        })
+    }
+
+    pub fn into_image(self) -> Image {
+        let depth = self.depth;
+
+        let data = self
+            .values
+            .par_chunks(depth)
+            .map(|x| match self.statistic {
+                OrderStatistic::Minimum => x[0],
+                // Both center values are the same for an odd depth
+                OrderStatistic::Median => ((x[(depth - 1) / 2] as f32 + x[depth / 2] as f32) / 2.0).round() as u16,
+                OrderStatistic::Brightest(_) => {
+                    ((x.iter().map(|v| *v as u32).sum::<u32>() as f32) / depth as f32).round() as u16
+                }
+            })
+            .collect();
+
+        self.template.with_data(data)
+    }
+}
+
+/// Merges two ascending lists into `merged`, remembering how many frames every value represents.
+fn merge_sorted(merged: &mut Vec<(u16, f32)>, x: &[u16], weight_x: f32, y: &[u16], weight_y: f32) {
+    merged.clear();
+
+    let (mut i, mut j) = (0, 0);
+    while i < x.len() || j < y.len() {
+        if j == y.len() || (i < x.len() && x[i] <= y[j]) {
+            merged.push((x[i], weight_x));
+            i += 1;
+        } else {
+            merged.push((y[j], weight_y));
+            j += 1;
+        }
+    }
+}
+
+fn copy_values(merged: &[(u16, f32)], res: &mut [u16]) {
+    res.iter_mut().zip(merged).for_each(|(r, (v, _))| *r = *v);
+}
+
+/// Reduces the weighted values to evenly spaced quantiles, or keeps them all if they fit.
+fn select_quantiles(merged: &[(u16, f32)], res: &mut [u16]) {
+    if merged.len() == res.len() {
+        return copy_values(merged, res);
+    }
+
+    let total: f32 = merged.iter().map(|(_, w)| w).sum();
+    let mut cumulated = 0.0;
+    let mut values = merged.iter().map(|(v, w)| {
+        cumulated += w;
+        (*v, cumulated)
+    });
+
+    let count = res.len() as f32;
+    let mut current = values.next().unwrap_or_default();
+    for (j, r) in res.iter_mut().enumerate() {
+        let target = (j as f32 + 0.5) * total / count;
+        while current.1 < target {
+            match values.next() {
+                Some(x) => current = x,
+                None => break,
+            }
+        }
+        *r = current.0;
+    }
+}
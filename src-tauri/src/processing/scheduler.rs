@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use log::{info, warn};
+
+/// Frames every worker holds besides the one it decodes: its accumulator and the frame that is merged
+const FRAMES_PER_WORKER: usize = 2;
+
+/// Number of workers and queued frames, such that a streaming merge stays within the memory budget
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    pub workers: usize,
+    /// Number of decoded frames per worker that wait to be merged
+    pub queue_length: usize,
+}
+
+impl Schedule {
+    /// Divides the budget in bytes into frames of the given size, where a frame takes the decoded size while it is
+    /// loaded, e.g. the whole frame of a strip.
+    ///
+    /// The budget is only approximate, as merging two frames briefly needs memory for the result.
+    pub fn new(budget: usize, frame_size: usize, decoded_size: usize, max_workers: usize) -> Schedule {
+        let frame_size = frame_size.max(1);
+        let worker_size = FRAMES_PER_WORKER * frame_size + decoded_size.max(frame_size);
+        if budget < worker_size {
+            warn!(
+                "The memory budget of {} MiB is too small for the {} MiB of frames per worker, merging them one by one",
+                budget >> 20,
+                worker_size >> 20
+            );
+        }
+
+        let workers = (budget / worker_size).clamp(1, max_workers.max(1));
+        let queue_length = ((budget / workers).saturating_sub(worker_size) / frame_size + 1).max(1);
+
+        Schedule { workers, queue_length }
+    }
+}
+
+/// Merges the loaded tasks in their order, keeping a bounded number of frames in memory.
+///
+/// The first task is loaded up front to measure the size of a frame. The tasks are split into consecutive ranges, one
+/// per worker. Every worker decodes its range on one thread and merges it into a single accumulator on another, such
+/// that decoding and merging overlap. The accumulators of the workers are merged in order at the end.
+pub fn merge_streaming<T, F>(
+    tasks: &[T],
+    budget: usize,
+    load: impl Fn(&T) -> anyhow::Result<F> + Sync,
+    merge: impl Fn(F, F) -> anyhow::Result<F> + Sync,
+    size: impl Fn(&F) -> usize,
+    decoded_size: impl Fn(&F) -> usize,
+) -> anyhow::Result<Option<F>>
+where
+    T: Sync,
+    F: Send,
+{
+    let (first, rest) = match tasks.split_first() {
+        Some((first, rest)) => (load(first)?, rest),
+        None => return Ok(None),
+    };
+
+    let max_workers = rayon::current_num_threads().min(rest.len());
+    let schedule = Schedule::new(budget, size(&first), decoded_size(&first), max_workers);
+    info!("Merging with {} workers and up to {} decoded frames each", schedule.workers, schedule.queue_length);
+
+    let failed = AtomicBool::new(false);
+    let chunk_size = rest.len().div_ceil(schedule.workers);
+    let mut initial = Some(first);
+
+    let results: Vec<anyhow::Result<Option<F>>> = thread::scope(|scope| {
+        let workers: Vec<_> = rest
+            .chunks(chunk_size.max(1))
+            .map(|chunk| {
+                let (sender, receiver) = mpsc::sync_channel::<anyhow::Result<F>>(schedule.queue_length - 1);
+                let (load, failed) = (&load, &failed);
+                scope.spawn(move || {
+                    for task in chunk {
+                        if failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        // The merging stopped, if the queue is closed
+                        if sender.send(load(task)).is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                let (merge, accumulator) = (&merge, initial.take());
+                scope.spawn(move || {
+                    let mut accumulator = accumulator;
+                    for frame in receiver {
+                        if failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        let merged = frame.and_then(|frame| match accumulator.take() {
+                            Some(x) => merge(x, frame),
+                            None => Ok(frame),
+                        });
+                        match merged {
+                            Ok(x) => accumulator = Some(x),
+                            Err(err) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(err);
+                            }
+                        }
+                    }
+                    Ok(accumulator)
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|_| anyhow::bail!("A merge worker panicked"))
+            })
+            .collect()
+    });
+
+    // Without further tasks, the first frame is the result
+    let mut result = initial;
+    for accumulator in results {
+        result = match (result, accumulator?) {
+            (Some(x), Some(y)) => Some(merge(x, y)?),
+            (x, y) => x.or(y),
+        };
+    }
+
+    Ok(result)
+}
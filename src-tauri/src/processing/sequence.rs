@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::image::Image;
+
+/// File formats for the images of an exported sequence
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SequenceFormat {
+    /// Merged RAW data
+    Dng,
+    /// Developed preview
+    Jpg,
+    /// Developed preview
+    Png,
+}
+
+/// Describes where and how the build-up of the star trails is exported, e.g. to create a timelapse.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SequenceExport {
+    pub directory: PathBuf,
+    pub formats: Vec<SequenceFormat>,
+}
+
+impl SequenceExport {
+    /// Writes the merge result of the first `index + 1` lightframes as numbered files.
+    pub fn write(&self, index: usize, image: Image) -> anyhow::Result<()> {
+        let writer = image.get_image_writer()?;
+
+        for format in &self.formats {
+            let path = self.directory.join(format!("{:05}.{}", index + 1, format.extension()));
+            match format {
+                SequenceFormat::Dng => writer.write_dng(path)?,
+                SequenceFormat::Jpg | SequenceFormat::Png => writer.write_preview_jpg(path)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SequenceFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SequenceFormat::Dng => "dng",
+            SequenceFormat::Jpg => "jpg",
+            SequenceFormat::Png => "png",
+        }
+    }
+}
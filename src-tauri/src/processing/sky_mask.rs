@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use image::GrayImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::image::Image;
+use crate::processing::stacking::SumStack;
+
+/// Where the sky mask comes from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskSource {
+    /// Image at the resolution of the raw data, where white marks the sky and black the foreground
+    File(PathBuf),
+    /// Estimated from the movement of the stars and the temporal variance of the lightframes
+    Detect,
+}
+
+/// Separates the sky from the foreground for the mask-dependent features
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkyMaskSettings {
+    pub source: MaskSource,
+    /// Radius in pixels over which sky and foreground blend into each other
+    #[serde(default)]
+    pub feather: usize,
+    /// Saves the mask as an image, e.g. to correct a detected mask by hand
+    #[serde(default)]
+    pub export: Option<PathBuf>,
+}
+
+/// How the foreground of a composite is stacked
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForegroundMode {
+    #[default]
+    Mean,
+    Median,
+}
+
+/// Per-pixel weight of the sky, from 0 for the foreground to 1 for the sky
+pub struct SkyMask {
+    pub width: usize,
+    pub height: usize,
+    weights: Vec<f32>,
+}
+
+impl SkyMask {
+    pub fn load(path: &Path) -> anyhow::Result<SkyMask> {
+        let mask = image::open(path)
+            .with_context(|| format!("Could not load mask {:#?}", path))?
+            .into_luma16();
+
+        Ok(SkyMask {
+            width: mask.width() as usize,
+            height: mask.height() as usize,
+            weights: mask.pixels().map(|p| p.0[0] as f32 / u16::MAX as f32).collect(),
+        })
+    }
+
+    /// Estimates the sky from the brightest values and the temporal statistics of the lightframes.
+    ///
+    /// Stars passing a pixel cause a peak far above the noise of its mean, while the foreground only varies by noise.
+    /// Changes of the sky like clouds show up as a high variance instead. As the stars only cover a fraction of the
+    /// sky, the share of such pixels in the neighbourhood decides whether a region belongs to the sky.
+    pub fn detect(maximum: &Image, statistics: &SumStack) -> anyhow::Result<SkyMask> {
+        let count = statistics.num_images();
+        anyhow::ensure!(count >= 3, "Detecting the sky needs at least three lightframes");
+
+        let (width, height, cpp) = maximum.size();
+        let (mean, deviation) = statistics.mean_and_deviation()?;
+        let maximum = maximum.image_data()?;
+        anyhow::ensure!(maximum.len() == mean.len(), "Images to detect the sky have different dimensions");
+
+        // Peaks of pure noise rarely exceed sqrt(2 ln n) standard deviations
+        let noise_peak = 1.5 * (2.0 * (count as f32).ln()).sqrt();
+        let typical_deviation = percentile(&deviation, 0.5);
+
+        let evidence: Vec<f32> = (0..width * height)
+            .into_par_iter()
+            .map(|pixel| {
+                let moving = (pixel * cpp..(pixel + 1) * cpp).any(|i| {
+                    let peak = maximum[i] as f32 - mean[i];
+                    (deviation[i] > 0.0 && peak > noise_peak * deviation[i]) || deviation[i] > 3.0 * typical_deviation
+                });
+                if moving {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        let density = SkyMask {
+            width,
+            height,
+            weights: evidence,
+        }
+        .feathered((width.max(height) / 100).max(1));
+
+        let threshold = 0.25 * percentile(&density.weights, 0.95);
+        let weights = density
+            .weights
+            .par_iter()
+            .map(|x| if threshold > 0.0 && *x > threshold { 1.0 } else { 0.0 })
+            .collect();
+
+        Ok(SkyMask { weights, ..density })
+    }
+
+    /// Writes the mask as a grayscale image, whose format is derived from the file extension.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let pixels = self.weights.iter().map(|x| (x * 255.0).round() as u8).collect();
+        let mask = GrayImage::from_raw(self.width as u32, self.height as u32, pixels).context("Invalid mask size")?;
+
+        mask.save(path)
+            .with_context(|| format!("Could not save mask {:#?}", path))
+    }
+
+    pub fn weight(&self, pixel: usize) -> f32 {
+        self.weights[pixel]
+    }
+
+    /// Softens the edges of the mask by blurring it three times with a box of the given radius, which is close to a
+    /// gaussian blur.
+    pub fn feathered(self, radius: usize) -> SkyMask {
+        if radius == 0 {
+            return self;
+        }
+
+        let mut weights = self.weights;
+        for _ in 0..3 {
+            let rows = blur_rows(&weights, self.width, radius);
+            let columns = blur_rows(&transpose(&rows, self.width, self.height), self.height, radius);
+            weights = transpose(&columns, self.height, self.width);
+        }
+
+        SkyMask { weights, ..self }
+    }
+}
+
+/// Averages every value with its neighbours within the radius in the same row.
+fn blur_rows(values: &[f32], width: usize, radius: usize) -> Vec<f32> {
+    let mut res = vec![0.0; values.len()];
+
+    res.par_chunks_mut(width)
+        .zip(values.par_chunks(width))
+        .for_each(|(out, row)| {
+            let mut cumulated = vec![0.0; width + 1];
+            for (i, v) in row.iter().enumerate() {
+                cumulated[i + 1] = cumulated[i] + v;
+            }
+
+            for (x, value) in out.iter_mut().enumerate() {
+                let start = x.saturating_sub(radius);
+                let end = (x + radius + 1).min(width);
+                *value = (cumulated[end] - cumulated[start]) / (end - start) as f32;
+            }
+        });
+
+    res
+}
+
+/// Returns the value at the given fraction of the sorted values.
+fn percentile(values: &[f32], fraction: f32) -> f32 {
+    let mut sorted = values.to_vec();
+    sorted.par_sort_unstable_by(|a, b| a.total_cmp(b));
+
+    match sorted.len() {
+        0 => 0.0,
+        n => sorted[((n - 1) as f32 * fraction).round() as usize],
+    }
+}
+
+fn transpose(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    (0..width)
+        .into_par_iter()
+        .flat_map_iter(|x| (0..height).map(move |y| values[y * width + x]))
+        .collect()
+}
@@ -0,0 +1,348 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::checkpoint::{self, ImagePart, StackPart};
+use crate::processing::image::{join_parts, Image, Mergable, MergeMode};
+use crate::processing::order_statistics::OrderStack;
+
+/// How the lightframes are combined into the resulting image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StackingMode {
+    /// Brightest value of every pixel, creating star trails
+    #[default]
+    Maximum,
+    /// Mean of all frames, reducing the noise
+    Mean,
+    /// Mean of the values within kappa standard deviations around the mean, rejecting outliers like planes
+    #[serde(rename = "sigma-clipped")]
+    SigmaClipped,
+    /// Mean after clamping the values to kappa standard deviations around the mean
+    Winsorized,
+    /// Median of every pixel, removing transient objects from the foreground
+    Median,
+    /// Darkest value of every pixel
+    Minimum,
+    /// Mean of the brightest values of every pixel, creating less noisy star trails
+    Brightest,
+}
+
+impl StackingMode {
+    /// Number of times the lightframes have to be loaded
+    pub fn passes(&self) -> usize {
+        match self {
+            StackingMode::SigmaClipped | StackingMode::Winsorized => 2,
+            StackingMode::Maximum
+            | StackingMode::Mean
+            | StackingMode::Median
+            | StackingMode::Minimum
+            | StackingMode::Brightest => 1,
+        }
+    }
+}
+
+/// Per-pixel range of values that are accepted in the second pass of the clipping modes.
+pub struct ClippingBounds {
+    lower: Vec<f32>,
+    upper: Vec<f32>,
+    winsorize: bool,
+}
+
+/// Accumulated lightframes of the stacking modes
+#[derive(Clone)]
+pub enum Stack {
+    Sum(SumStack),
+    Order(OrderStack),
+}
+
+impl Stack {
+    pub fn merge(self, other: Stack) -> anyhow::Result<Stack> {
+        match (self, other) {
+            (Stack::Sum(x), Stack::Sum(y)) => Ok(Stack::Sum(x.merge(y)?)),
+            (Stack::Order(x), Stack::Order(y)) => Ok(Stack::Order(x.merge(y)?)),
+            _ => anyhow::bail!("Stacks of different modes can't be merged"),
+        }
+    }
+
+    pub fn into_image(self) -> Image {
+        match self {
+            Stack::Sum(x) => x.into_image(),
+            Stack::Order(x) => x.into_image(),
+        }
+    }
+
+    /// Joins the stacks of horizontal strips from top to bottom.
+    pub fn join_strips(strips: Vec<Stack>) -> anyhow::Result<Stack> {
+        match strips.first() {
+            Some(Stack::Sum(_)) => {
+                let strips = strips
+                    .into_iter()
+                    .map(|x| match x {
+                        Stack::Sum(x) => Ok(x),
+                        Stack::Order(_) => anyhow::bail!("Stacks of different modes can't be joined"),
+                    })
+                    .collect::<anyhow::Result<Vec<SumStack>>>()?;
+                Ok(Stack::Sum(SumStack::join_strips(strips)?))
+            }
+            Some(Stack::Order(_)) => {
+                let strips = strips
+                    .into_iter()
+                    .map(|x| match x {
+                        Stack::Order(x) => Ok(x),
+                        Stack::Sum(_) => anyhow::bail!("Stacks of different modes can't be joined"),
+                    })
+                    .collect::<anyhow::Result<Vec<OrderStack>>>()?;
+                Ok(Stack::Order(OrderStack::join_strips(strips)?))
+            }
+            None => anyhow::bail!("There are no strips to join"),
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Stack::Sum(x) => x.memory_size(),
+            Stack::Order(x) => x.memory_size(),
+        }
+    }
+
+    pub fn save(&self, directory: &Path, name: &str) -> anyhow::Result<StackPart> {
+        match self {
+            Stack::Sum(x) => x.save(directory, name),
+            Stack::Order(x) => x.save(directory, name),
+        }
+    }
+
+    /// Loads a saved stack, where clipped stacks get the bounds of the current merge.
+    pub fn load(directory: &Path, part: &StackPart, bounds: Option<&Arc<ClippingBounds>>) -> anyhow::Result<Stack> {
+        match part {
+            StackPart::Sum { image, squares, counts } => {
+                Ok(Stack::Sum(SumStack::load(directory, image, *squares, *counts, bounds)?))
+            }
+            StackPart::Order {
+                image,
+                statistic,
+                depth,
+                capacity,
+            } => Ok(Stack::Order(OrderStack::load(directory, image, *statistic, *depth, *capacity)?)),
+        }
+    }
+}
+
+/// Per-pixel sums of stacked frames, which can be merged in any order.
+#[derive(Clone)]
+pub struct SumStack {
+    /// Metadata of the stacked frames without image data
+    template: Image,
+    sums: Vec<u32>,
+    /// Sums of the squared values, if statistics are collected
+    squares: Option<Vec<u64>>,
+    /// Number of values per pixel, if values have been rejected
+    counts: Option<Vec<u16>>,
+    bounds: Option<Arc<ClippingBounds>>,
+}
+
+impl SumStack {
+    pub fn from_image(image: Image, collect_statistics: bool) -> anyhow::Result<SumStack> {
+        let data = image.image_data()?;
+        let squares = collect_statistics.then(|| data.iter().map(|x| *x as u64 * *x as u64).collect());
+
+        Ok(SumStack {
+            sums: data.iter().map(|x| *x as u32).collect(),
+            squares,
+            counts: None,
+            bounds: None,
+            template: image.with_data(Vec::new()),
+        })
+    }
+
+    /// Stacks only the values within the bounds, or clamps them to the bounds for winsorizing.
+    pub fn clipped(image: Image, bounds: &Arc<ClippingBounds>) -> anyhow::Result<SumStack> {
+        let data = image.image_data()?;
+        anyhow::ensure!(data.len() == bounds.lower.len(), "Images to stack have different dimensions");
+
+        let (sums, counts) = data
+            .par_iter()
+            .zip(bounds.lower.par_iter().zip(bounds.upper.par_iter()))
+            .map(|(x, (lower, upper))| {
+                let value = *x as f32;
+                match (bounds.winsorize, (*lower..=*upper).contains(&value)) {
+                    (true, _) => (value.clamp(*lower, *upper).round() as u32, 1u16),
+                    (false, true) => (*x as u32, 1),
+                    (false, false) => (0, 0),
+                }
+            })
+            .unzip();
+
+        Ok(SumStack {
+            sums,
+            squares: None,
+            counts: Some(counts),
+            bounds: Some(bounds.clone()),
+            template: image.with_data(Vec::new()),
+        })
+    }
+
+    /// Joins the stacks of horizontal strips from top to bottom, together with their statistics and bounds.
+    pub fn join_strips(strips: Vec<SumStack>) -> anyhow::Result<SumStack> {
+        let winsorize = strips.iter().any(|x| matches!(&x.bounds, Some(b) if b.winsorize));
+        let mut templates = Vec::new();
+        let (mut sums, mut squares, mut counts, mut bounds) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for strip in strips {
+            templates.push(strip.template);
+            sums.push(strip.sums);
+            squares.push(strip.squares);
+            counts.push(strip.counts);
+            bounds.push(strip.bounds);
+        }
+
+        let bounds = join_parts(bounds, |x| {
+            Ok(Arc::new(ClippingBounds {
+                lower: x.iter().flat_map(|b| b.lower.iter().copied()).collect(),
+                upper: x.iter().flat_map(|b| b.upper.iter().copied()).collect(),
+                winsorize,
+            }))
+        })?;
+
+        Ok(SumStack {
+            template: Image::join_strips(templates)?,
+            sums: sums.concat(),
+            squares: join_parts(squares, |x| Ok(x.concat()))?,
+            counts: join_parts(counts, |x| Ok(x.concat()))?,
+            bounds,
+        })
+    }
+
+    pub fn merge(self, other: SumStack) -> anyhow::Result<SumStack> {
+        anyhow::ensure!(self.sums.len() == other.sums.len(), "Images to stack have different dimensions");
+
+        Ok(SumStack {
+            sums: add(self.sums, other.sums),
+            squares: self.squares.zip(other.squares).map(|(x, y)| add(x, y)),
+            counts: self.counts.zip(other.counts).map(|(x, y)| add(x, y)),
+            bounds: self.bounds,
+            template: self.template.merge(other.template, MergeMode::Maximize)?,
+        })
+    }
+
+    /// Derives the range of accepted values per pixel from the mean and standard deviation of the stack.
+    pub fn clipping_bounds(&self, kappa: f32, mode: StackingMode) -> anyhow::Result<ClippingBounds> {
+        let (mean, sigma) = self.mean_and_deviation()?;
+
+        let (lower, upper) = mean
+            .par_iter()
+            .zip(sigma.par_iter())
+            .map(|(mean, sigma)| (mean - kappa * sigma, mean + kappa * sigma))
+            .unzip();
+
+        Ok(ClippingBounds {
+            lower,
+            upper,
+            winsorize: mode == StackingMode::Winsorized,
+        })
+    }
+
+    /// Computes the mean and the standard deviation of every pixel from the collected statistics.
+    pub fn mean_and_deviation(&self) -> anyhow::Result<(Vec<f32>, Vec<f32>)> {
+        let squares = match &self.squares {
+            Some(x) => x,
+            None => anyhow::bail!("The stack has no statistics"),
+        };
+        let count = self.template.num_images() as f64;
+
+        Ok(self
+            .sums
+            .par_iter()
+            .zip(squares.par_iter())
+            .map(|(sum, square)| {
+                let mean = *sum as f64 / count;
+                let sigma = (*square as f64 / count - mean * mean).max(0.0).sqrt();
+                (mean as f32, sigma as f32)
+            })
+            .unzip())
+    }
+
+    pub fn num_images(&self) -> usize {
+        self.template.num_images()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.sums.len() * std::mem::size_of::<u32>()
+            + self
+                .squares
+                .as_ref()
+                .map_or(0, |x| x.len() * std::mem::size_of::<u64>())
+            + self.counts.as_ref().map_or(0, |x| x.len() * std::mem::size_of::<u16>())
+    }
+
+    /// Writes the sums, and the squares and counts if there are any, next to the current mean.
+    pub fn save(&self, directory: &Path, name: &str) -> anyhow::Result<StackPart> {
+        let image = self.template.save_metadata(directory, name)?;
+        checkpoint::write_samples(&image.path(directory, "sums"), &self.sums)?;
+        if let Some(squares) = &self.squares {
+            checkpoint::write_samples(&image.path(directory, "squares"), squares)?;
+        }
+        if let Some(counts) = &self.counts {
+            checkpoint::write_samples(&image.path(directory, "counts"), counts)?;
+        }
+
+        Ok(StackPart::Sum {
+            image,
+            squares: self.squares.is_some(),
+            counts: self.counts.is_some(),
+        })
+    }
+
+    fn load(
+        directory: &Path,
+        image: &ImagePart,
+        squares: bool,
+        counts: bool,
+        bounds: Option<&Arc<ClippingBounds>>,
+    ) -> anyhow::Result<SumStack> {
+        let template = Image::load(directory, image)?;
+        let size = template.image_data()?.len();
+        anyhow::ensure!(!counts || bounds.is_some(), "The clipped stack {:#?} needs clipping bounds", image.name);
+
+        Ok(SumStack {
+            sums: checkpoint::read_samples(&image.path(directory, "sums"), size)?,
+            squares: match squares {
+                true => Some(checkpoint::read_samples(&image.path(directory, "squares"), size)?),
+                false => None,
+            },
+            counts: match counts {
+                true => Some(checkpoint::read_samples(&image.path(directory, "counts"), size)?),
+                false => None,
+            },
+            bounds: bounds.filter(|_| counts).cloned(),
+            template: template.with_data(Vec::new()),
+        })
+    }
+
+    /// Computes the mean of every pixel.
+    ///
+    /// Pixels without any accepted values fall back to the center of their bounds, which is the unclipped mean.
+    pub fn into_image(self) -> Image {
+        let count = self.template.num_images() as u32;
+
+        let data = self
+            .sums
+            .par_iter()
+            .enumerate()
+            .map(|(i, sum)| match (&self.counts, &self.bounds) {
+                (Some(counts), Some(b)) if counts[i] == 0 => ((b.lower[i] + b.upper[i]) / 2.0).round() as u16,
+                (Some(counts), _) => (*sum as f32 / counts[i].max(1) as f32).round() as u16,
+                (None, _) => (*sum as f32 / count.max(1) as f32).round() as u16,
+            })
+            .collect();
+
+        self.template.with_data(data)
+    }
+}
+
+fn add<T: std::ops::Add<Output = T> + Copy + Send + Sync>(x: Vec<T>, y: Vec<T>) -> Vec<T> {
+    x.par_iter().zip(y.par_iter()).map(|(a, b)| *a + *b).collect()
+}
@@ -1,6 +1,5 @@
 use log::{debug, warn};
 use serde_json::{json, Map};
-use std::cmp::max;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
@@ -137,7 +136,12 @@ impl InfoLoadingStatus {
 
 pub struct ProcessingStatus {
     pub count_lights: usize,
-    pub count_darks: usize,
+    /// Number of calibration frames of all types
+    pub count_calibration: usize,
+    /// Number of files to load over all passes
+    count_load_tasks: usize,
+    /// Number of merges over all passes
+    count_merge_tasks: usize,
     aborted_status: Arc<AtomicBool>,
     count_loaded_lights: Arc<AtomicUsize>,
     count_loading_lights: Arc<AtomicUsize>,
@@ -150,7 +154,9 @@ impl Status for ProcessingStatus {
     fn json(&self) -> serde_json::Value {
         json!({
             "count_lights": self.count_lights,
-            "count_darks": self.count_darks,
+            "count_calibration": self.count_calibration,
+            "count_load_tasks": self.count_load_tasks,
+            "count_merge_tasks": self.count_merge_tasks,
             "count_loaded_lights": self.count_loaded_lights.load(Relaxed),
             "count_loading_lights": self.count_loading_lights.load(Relaxed),
             "count_merged": self.count_merge_completed.load(Relaxed),
@@ -180,25 +186,51 @@ impl Status for ProcessingStatus {
 impl ProcessingStatus {
     pub fn new(
         count_lights: usize,
-        count_darks: usize,
+        count_calibration: &[usize],
+        passes: usize,
         callback_event: String,
         window: Option<Window>,
     ) -> Arc<Mutex<Self>> {
-        let status = Arc::new(Mutex::new(ProcessingStatus {
+        // The lightframes are loaded and merged once per pass, the calibration frames only once per type
+        let count_load_tasks = passes * count_lights + count_calibration.iter().sum::<usize>();
+        let count_merge_tasks = passes * count_lights.saturating_sub(1)
+            + count_calibration.iter().map(|x| x.saturating_sub(1)).sum::<usize>();
+
+        let status = Self::with_counts(count_lights, count_calibration, count_load_tasks, count_merge_tasks, "Merging");
+        if let Some(w) = window {
+            Self::start_update_emitter(status.clone(), callback_event, w);
+        }
+        status
+    }
+
+    /// Creates the status of a scan, where every lightframe is loaded and scanned once instead of being merged.
+    pub fn scanning(count_lights: usize, callback_event: String, window: Option<Window>) -> Arc<Mutex<Self>> {
+        let status = Self::with_counts(count_lights, &[], count_lights, count_lights, "Scanning");
+        if let Some(w) = window {
+            Self::start_update_emitter(status.clone(), callback_event, w);
+        }
+        status
+    }
+
+    fn with_counts(
+        count_lights: usize,
+        count_calibration: &[usize],
+        count_load_tasks: usize,
+        count_merge_tasks: usize,
+        step: &'static str,
+    ) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(ProcessingStatus {
             count_lights,
-            count_darks,
+            count_calibration: count_calibration.iter().sum(),
+            count_load_tasks,
+            count_merge_tasks,
             aborted_status: Arc::new(AtomicBool::new(false)),
             count_loaded_lights: Arc::new(AtomicUsize::new(0)),
             count_loading_lights: Arc::new(AtomicUsize::new(0)),
             count_merge_completed: Arc::new(AtomicUsize::new(0)),
             count_merging: Arc::new(AtomicUsize::new(0)),
-            cli_progress: ProcessingStatusCli::new(count_lights as u64, count_darks as u64),
-        }));
-
-        if let Some(w) = window {
-            Self::start_update_emitter(status.clone(), callback_event, w);
-        }
-        status
+            cli_progress: ProcessingStatusCli::new(count_load_tasks as u64, count_merge_tasks as u64, step),
+        }))
     }
 
     pub fn abort(&self) {
@@ -207,12 +239,11 @@ impl ProcessingStatus {
     }
 
     pub fn loading_done(&self) -> bool {
-        self.count_lights + self.count_darks == self.count_loaded_lights.load(Relaxed)
+        self.count_load_tasks == self.count_loaded_lights.load(Relaxed)
     }
 
     pub fn merging_done(&self) -> bool {
-        self.count_lights - 1 + max(0isize, self.count_darks as isize - 1) as usize
-            == self.count_merge_completed.load(Relaxed)
+        self.count_merge_tasks == self.count_merge_completed.load(Relaxed)
     }
 
     pub fn start_loading(&self) {
@@ -226,17 +257,43 @@ impl ProcessingStatus {
         self.print_status();
     }
 
+    /// Adds tasks that are only known during the processing, e.g. for appending to an earlier result.
+    pub fn add_tasks(&mut self, count_load_tasks: usize, count_merge_tasks: usize) {
+        self.count_load_tasks += count_load_tasks;
+        self.count_merge_tasks += count_merge_tasks;
+        self.cli_progress
+            .set_lengths(self.count_load_tasks as u64, self.count_merge_tasks as u64);
+    }
+
+    /// Counts a file that doesn't have to be loaded, e.g. as it is included in a checkpoint.
+    pub fn skip_loading(&self) {
+        self.count_loaded_lights.fetch_add(1, Relaxed);
+        self.print_status();
+    }
+
     pub fn start_merging(&self) {
         self.count_merging.fetch_add(1, Relaxed);
         self.print_status();
     }
 
+    /// Counts a merge that is left out, e.g. for a rejected lightframe.
+    pub fn skip_merging(&self) {
+        self.count_merge_completed.fetch_add(1, Relaxed);
+        self.print_status();
+    }
+
     pub fn finish_merging(&self) {
         self.count_merge_completed.fetch_add(1, Relaxed);
         self.count_merging.fetch_sub(1, Relaxed);
         self.print_status();
     }
 
+    /// Counts a scanned lightframe of a scan, which takes the place of the merges.
+    pub fn finish_scanning(&self) {
+        self.count_merge_completed.fetch_add(1, Relaxed);
+        self.print_status();
+    }
+
     fn print_status(&self) {
         self.cli_progress.update(
             self.count_loaded_lights.load(Relaxed) as u64,
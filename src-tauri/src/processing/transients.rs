@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::processing::gap_filling::SampleLayout;
+
+/// Settings to detect the streaks of satellites, planes and meteors
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreakDetection {
+    /// Number of noise standard deviations by which a streak exceeds the neighbouring frames
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Minimum length of a streak in pixels, which has to exceed the trail of a star within one frame
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+}
+
+pub fn default_threshold() -> f32 {
+    5.0
+}
+
+pub fn default_min_length() -> usize {
+    200
+}
+
+/// Lightframe whose transient streaks were replaced
+#[derive(Clone, Debug, Serialize)]
+pub struct TransientReport {
+    pub path: PathBuf,
+    pub streaks: usize,
+}
+
+/// Region of a frame in pixels
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Samples that belong to the detected streaks of a frame
+pub struct Streaks {
+    pub mask: Vec<bool>,
+    pub regions: Vec<BoundingBox>,
+}
+
+/// Grid of blocks that each cover one repetition of the CFA pattern, such that all colors are combined.
+struct Blocks {
+    width: usize,
+    height: usize,
+}
+
+/// Finds linear streaks that are only present in the current frame but in none of its neighbours.
+///
+/// Moving stars also leave a new trail segment in every frame, so only connected regions that are much longer than
+/// wide and longer than the minimum length count as streaks. The detected regions are widened by one block to cover
+/// the faint edges of the streaks.
+pub fn detect(current: &[u16], neighbours: &[&[u16]], layout: &SampleLayout, settings: &StreakDetection) -> Streaks {
+    let step_x = layout.step_x.max(1);
+    let step_y = layout.step_y.max(1);
+    let blocks = Blocks {
+        width: layout.row_length / layout.cpp / step_x,
+        height: current.len() / layout.row_length / step_y,
+    };
+
+    // Brightness that exceeds all neighbouring frames
+    let excess: Vec<f32> = (0..blocks.width * blocks.height)
+        .into_par_iter()
+        .map(|block| {
+            let samples = block_samples(block, &blocks, layout);
+            let value = |data: &[u16]| samples.iter().map(|i| data[*i] as f32).sum::<f32>();
+            let reference = neighbours.iter().map(|n| value(n)).fold(0.0, f32::max);
+            value(current) - reference
+        })
+        .collect();
+
+    // Robust estimation of the noise by the median absolute deviation
+    let sigma = 1.4826 * median(excess.iter().map(|x| x.abs()).collect());
+    let candidates: Vec<bool> = excess
+        .iter()
+        .map(|x| *x > settings.threshold * sigma.max(1.0))
+        .collect();
+
+    // Widen the candidates to connect interrupted streaks, like the blinking lights of planes
+    let candidates = dilate(&candidates, &blocks);
+
+    let min_length = settings.min_length as f32 / step_x.max(step_y) as f32;
+    let mut streak_blocks = vec![false; candidates.len()];
+    let mut regions = Vec::new();
+    for component in components(&candidates, &blocks) {
+        let (length, width) = extent(&component, &blocks);
+        if length >= min_length && length >= 4.0 * width {
+            component.iter().for_each(|b| streak_blocks[*b] = true);
+            regions.push(bounding_box(&component, &blocks, step_x, step_y));
+        }
+    }
+
+    let mut mask = vec![false; current.len()];
+    for (block, _) in streak_blocks.iter().enumerate().filter(|(_, x)| **x) {
+        block_samples(block, &blocks, layout)
+            .into_iter()
+            .for_each(|i| mask[i] = true);
+    }
+
+    Streaks { mask, regions }
+}
+
+fn block_samples(block: usize, blocks: &Blocks, layout: &SampleLayout) -> Vec<usize> {
+    let step_x = layout.step_x.max(1);
+    let step_y = layout.step_y.max(1);
+    let (bx, by) = (block % blocks.width, block / blocks.width);
+
+    let mut samples = Vec::with_capacity(step_x * step_y * layout.cpp);
+    for y in by * step_y..(by + 1) * step_y {
+        let row = y * layout.row_length;
+        samples.extend(row + bx * step_x * layout.cpp..row + (bx + 1) * step_x * layout.cpp);
+    }
+
+    samples
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let center = values.len() / 2;
+    *values.select_nth_unstable_by(center, |a, b| a.total_cmp(b)).1
+}
+
+fn dilate(values: &[bool], blocks: &Blocks) -> Vec<bool> {
+    (0..values.len())
+        .into_par_iter()
+        .map(|i| neighbours(i, blocks).chain(std::iter::once(i)).any(|n| values[n]))
+        .collect()
+}
+
+/// Returns the indices of the eight surrounding blocks.
+fn neighbours(i: usize, blocks: &Blocks) -> impl Iterator<Item = usize> + '_ {
+    let (x, y) = ((i % blocks.width) as isize, (i / blocks.width) as isize);
+
+    (-1..=1)
+        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(move |(nx, ny)| {
+            (*nx, *ny) != (x, y) && (0..blocks.width as isize).contains(nx) && (0..blocks.height as isize).contains(ny)
+        })
+        .map(move |(nx, ny)| ny as usize * blocks.width + nx as usize)
+}
+
+/// Groups the set blocks into connected components.
+fn components(values: &[bool], blocks: &Blocks) -> Vec<Vec<usize>> {
+    let mut visited = vec![false; values.len()];
+    let mut components = Vec::new();
+
+    for start in 0..values.len() {
+        if !values[start] || visited[start] {
+            continue;
+        }
+
+        visited[start] = true;
+        let mut component = Vec::new();
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            component.push(i);
+            for n in neighbours(i, blocks) {
+                if values[n] && !visited[n] {
+                    visited[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+        components.push(component);
+    }
+
+    components
+}
+
+fn bounding_box(component: &[usize], blocks: &Blocks, step_x: usize, step_y: usize) -> BoundingBox {
+    let xs = component.iter().map(|i| i % blocks.width);
+    let ys = component.iter().map(|i| i / blocks.width);
+    let (min_x, max_x) = (xs.clone().min().unwrap_or(0), xs.max().unwrap_or(0));
+    let (min_y, max_y) = (ys.clone().min().unwrap_or(0), ys.max().unwrap_or(0));
+
+    BoundingBox {
+        x: min_x * step_x,
+        y: min_y * step_y,
+        width: (max_x - min_x + 1) * step_x,
+        height: (max_y - min_y + 1) * step_y,
+    }
+}
+
+/// Estimates the length and width of a component from the variances along its principal axes.
+///
+/// A line of length l has a variance of l²/12 along its direction.
+fn extent(component: &[usize], blocks: &Blocks) -> (f32, f32) {
+    let n = component.len() as f32;
+    let points = component
+        .iter()
+        .map(|i| ((i % blocks.width) as f32, (i / blocks.width) as f32));
+
+    let (sum_x, sum_y) = points.clone().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (xx, yy, xy) = points.fold((0.0, 0.0, 0.0), |(xx, yy, xy), (x, y)| {
+        let (dx, dy) = (x - mean_x, y - mean_y);
+        (xx + dx * dx / n, yy + dy * dy / n, xy + dx * dy / n)
+    });
+
+    // Eigenvalues of the covariance matrix
+    let center = (xx + yy) / 2.0;
+    let spread = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    let length = (12.0 * (center + spread)).sqrt().max(1.0);
+    let width = (12.0 * (center - spread).max(0.0)).sqrt().max(1.0);
+
+    (length, width)
+}